@@ -1,5 +1,6 @@
 use std::fs;
 use std::env;
+use std::process::Command;
 
 fn main() {
     // --- 1. Configure Linker Arguments ---
@@ -39,4 +40,25 @@ fn main() {
     println!("cargo:rerun-if-changed=memory.x");
 
     println!("Selected memory feature: {}", feature_name);
+
+
+    // --- 5. Bake Firmware Metadata ---
+    // Read by the Device Information Service through env!()
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short=8", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+
+    // Board revision can be overridden per build, e.g. HW_REVISION=2 cargo build
+    let hw_revision = env::var("HW_REVISION").unwrap_or_else(|_| "1".to_string());
+    println!("cargo:rustc-env=HW_REVISION={}", hw_revision);
+
+    println!("cargo:rerun-if-env-changed=HW_REVISION");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
 }
\ No newline at end of file
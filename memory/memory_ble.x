@@ -12,6 +12,10 @@ MEMORY
   */
 
   /* These values correspond to the NRF52840 with Softdevices S140 7.3.0 */
-  FLASH : ORIGIN = 0x00027000, LENGTH = 868K  
+  /* Top 80K is carved out of the 868K for the bond store, history log and config store (system::storage) */
+  FLASH : ORIGIN = 0x00027000, LENGTH = 788K
+  BOND_STORE : ORIGIN = 0x000EC000, LENGTH = 8K
+  HISTORY : ORIGIN = 0x000EE000, LENGTH = 64K
+  CONFIG_STORE : ORIGIN = 0x000FE000, LENGTH = 8K
   RAM : ORIGIN = 0x20020000, LENGTH = 128K    
 }
\ No newline at end of file
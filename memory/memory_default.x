@@ -6,7 +6,11 @@ MEMORY
   /* If the first section is used, Softdevices (BLE) will be overwritten */
 
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x00000000, LENGTH = 1024K
+  /* Top 80K is kept for the bond store, history log and config store (system::storage) */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 944K
+  BOND_STORE : ORIGIN = 0x000EC000, LENGTH = 8K
+  HISTORY : ORIGIN = 0x000EE000, LENGTH = 64K
+  CONFIG_STORE : ORIGIN = 0x000FE000, LENGTH = 8K
   RAM : ORIGIN = 0x20000000, LENGTH = 256K
 
   /* These values correspond to the NRF52840 with Softdevices S140 7.3.0 */
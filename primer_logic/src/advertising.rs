@@ -0,0 +1,226 @@
+// Advertising and scan response payloads
+//
+// AdvertisementBuilder appends AD structures ([len, type, data...]) in call order and checks them against the
+// payload limit. Fields after scan_response() go to the scan response instead of the advertisement.
+// Pure byte building, nothing here touches the SoftDevice - see tests/advertising.rs
+//
+// e.g. AdvertisementBuilder::new(AdvLimit::Legacy)
+//          .flags(FLAGS_GENERAL)
+//          .name("nRF52 Sensor")
+//          .scan_response()
+//          .services_128(&[SENSOR_SERVICE_UUID], true)
+//          .build()
+use heapless::Vec;
+
+// Payload sizes accepted by the S140 SoftDevice
+pub const LEGACY_ADV_LEN: usize = 31;
+pub const EXTENDED_ADV_LEN: usize = 255;
+pub const EXTENDED_CONNECTABLE_ADV_LEN: usize = 238;
+
+// AD types - Bluetooth SIG Assigned Numbers, Common Data Types
+pub const AD_FLAGS: u8 = 0x01;
+pub const AD_INCOMPLETE_16: u8 = 0x02;
+pub const AD_COMPLETE_16: u8 = 0x03;
+pub const AD_INCOMPLETE_128: u8 = 0x06;
+pub const AD_COMPLETE_128: u8 = 0x07;
+pub const AD_SHORT_NAME: u8 = 0x08;
+pub const AD_COMPLETE_NAME: u8 = 0x09;
+pub const AD_TX_POWER: u8 = 0x0A;
+pub const AD_SERVICE_DATA_16: u8 = 0x16;
+pub const AD_APPEARANCE: u8 = 0x19;
+pub const AD_SERVICE_DATA_128: u8 = 0x21;
+pub const AD_MANUFACTURER_DATA: u8 = 0xFF;
+
+// Flags bits
+pub const FLAG_LE_LIMITED_DISC: u8 = 0x01;
+pub const FLAG_LE_GENERAL_DISC: u8 = 0x02;
+pub const FLAG_BR_EDR_NOT_SUPPORTED: u8 = 0x04;
+pub const FLAGS_GENERAL: u8 = FLAG_LE_GENERAL_DISC | FLAG_BR_EDR_NOT_SUPPORTED;
+
+// Appearance values - Bluetooth SIG Assigned Numbers
+pub const APPEARANCE_GENERIC_SENSOR: u16 = 0x0540;
+pub const APPEARANCE_MULTI_SENSOR: u16 = 0x0552;
+
+// 0xFFFF is reserved for internal use and testing, swap for an assigned company ID before shipping
+pub const COMPANY_ID: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdvLimit {
+    Legacy,                 // Legacy advertising PDUs, also the limit for the scan response
+    Extended,               // Extended non-connectable advertising
+    ExtendedConnectable,    // Extended connectable advertising
+}
+
+impl AdvLimit {
+    pub const fn max_len(self) -> usize {
+        match self {
+            AdvLimit::Legacy => LEGACY_ADV_LEN,
+            AdvLimit::Extended => EXTENDED_ADV_LEN,
+            AdvLimit::ExtendedConnectable => EXTENDED_CONNECTABLE_ADV_LEN,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdvertisementError {
+    TooLong,        // The field doesn't fit in what is left of the payload
+    Empty,          // Empty name or UUID list
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Advertisement {
+    pub adv_data: Vec<u8, EXTENDED_ADV_LEN>,
+    pub scan_data: Vec<u8, EXTENDED_ADV_LEN>,
+}
+
+pub struct AdvertisementBuilder {
+    limit: AdvLimit,
+    adv_data: Vec<u8, EXTENDED_ADV_LEN>,
+    scan_data: Vec<u8, EXTENDED_ADV_LEN>,
+    in_scan_response: bool,
+    error: Option<AdvertisementError>,     // First failure, reported by build()
+}
+
+impl AdvertisementBuilder {
+    pub const fn new(limit: AdvLimit) -> Self {
+        AdvertisementBuilder {
+            limit,
+            adv_data: Vec::new(),
+            scan_data: Vec::new(),
+            in_scan_response: false,
+            error: None,
+        }
+    }
+
+    // Following fields go to the scan response
+    pub fn scan_response(mut self) -> Self {
+        self.in_scan_response = true;
+        self
+    }
+
+    pub fn flags(self, flags: u8) -> Self {
+        self.field(AD_FLAGS, &[flags])
+    }
+
+    // Complete local name, shortened to what is left of the payload when it doesn't fit
+    // Add it last so it gets the remaining space
+    pub fn name(self, name: &str) -> Self {
+        let available = self.remaining().saturating_sub(2);
+        if name.len() <= available {
+            return self.field(AD_COMPLETE_NAME, name.as_bytes());
+        }
+        let cut = floor_char_boundary(name, available);
+        self.short_name(&name[..cut])
+    }
+
+    pub fn short_name(self, name: &str) -> Self {
+        if name.is_empty() {
+            return self.fail(AdvertisementError::Empty);
+        }
+        self.field(AD_SHORT_NAME, name.as_bytes())
+    }
+
+    // complete = false marks the list as incomplete, there are more services than listed
+    pub fn services_16(self, uuids: &[u16], complete: bool) -> Self {
+        let ad_type = if complete { AD_COMPLETE_16 } else { AD_INCOMPLETE_16 };
+        let mut data: Vec<u8, EXTENDED_ADV_LEN> = Vec::new();
+        for uuid in uuids {
+            if data.extend_from_slice(&uuid.to_le_bytes()).is_err() {
+                return self.fail(AdvertisementError::TooLong);
+            }
+        }
+        self.list(ad_type, &data)
+    }
+
+    // UUIDs as written, e.g. 0x9e7312e0_2354_11eb_9f10_fbc30a62cf38 - sent little-endian
+    pub fn services_128(self, uuids: &[u128], complete: bool) -> Self {
+        let ad_type = if complete { AD_COMPLETE_128 } else { AD_INCOMPLETE_128 };
+        let mut data: Vec<u8, EXTENDED_ADV_LEN> = Vec::new();
+        for uuid in uuids {
+            if data.extend_from_slice(&uuid.to_le_bytes()).is_err() {
+                return self.fail(AdvertisementError::TooLong);
+            }
+        }
+        self.list(ad_type, &data)
+    }
+
+    // dBm
+    pub fn tx_power(self, tx_power: i8) -> Self {
+        self.field(AD_TX_POWER, &tx_power.to_le_bytes())
+    }
+
+    pub fn appearance(self, appearance: u16) -> Self {
+        self.field(AD_APPEARANCE, &appearance.to_le_bytes())
+    }
+
+    pub fn service_data_16(self, uuid: u16, data: &[u8]) -> Self {
+        self.prefixed(AD_SERVICE_DATA_16, &uuid.to_le_bytes(), data)
+    }
+
+    pub fn service_data_128(self, uuid: u128, data: &[u8]) -> Self {
+        self.prefixed(AD_SERVICE_DATA_128, &uuid.to_le_bytes(), data)
+    }
+
+    pub fn manufacturer_data(self, company_id: u16, data: &[u8]) -> Self {
+        self.prefixed(AD_MANUFACTURER_DATA, &company_id.to_le_bytes(), data)
+    }
+
+    pub fn build(self) -> Result<Advertisement, AdvertisementError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(Advertisement { adv_data: self.adv_data, scan_data: self.scan_data }),
+        }
+    }
+
+    // Bytes left in the payload being written - the scan response always has the legacy limit
+    fn remaining(&self) -> usize {
+        if self.in_scan_response {
+            LEGACY_ADV_LEN.saturating_sub(self.scan_data.len())
+        } else {
+            self.limit.max_len().saturating_sub(self.adv_data.len())
+        }
+    }
+
+    fn list(self, ad_type: u8, data: &[u8]) -> Self {
+        if data.is_empty() {
+            return self.fail(AdvertisementError::Empty);
+        }
+        self.field(ad_type, data)
+    }
+
+    fn prefixed(self, ad_type: u8, prefix: &[u8], data: &[u8]) -> Self {
+        let mut value: Vec<u8, EXTENDED_ADV_LEN> = Vec::new();
+        if value.extend_from_slice(prefix).is_err() || value.extend_from_slice(data).is_err() {
+            return self.fail(AdvertisementError::TooLong);
+        }
+        self.field(ad_type, &value)
+    }
+
+    fn field(mut self, ad_type: u8, data: &[u8]) -> Self {
+        if self.error.is_some() {
+            return self;
+        }
+        if data.len() + 2 > self.remaining() {
+            return self.fail(AdvertisementError::TooLong);
+        }
+
+        let payload = if self.in_scan_response { &mut self.scan_data } else { &mut self.adv_data };
+        // Length checked above, the pushes can't fail
+        let _ = payload.push(data.len() as u8 + 1);
+        let _ = payload.push(ad_type);
+        let _ = payload.extend_from_slice(data);
+        self
+    }
+
+    fn fail(mut self, error: AdvertisementError) -> Self {
+        self.error.get_or_insert(error);
+        self
+    }
+}
+
+// Longest prefix of name no longer than max bytes that ends on a character boundary
+fn floor_char_boundary(name: &str, max: usize) -> usize {
+    (0..=max.min(name.len())).rev().find(|&i| name.is_char_boundary(i)).unwrap_or(0)
+}
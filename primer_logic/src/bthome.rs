@@ -0,0 +1,106 @@
+// BTHome v2 service data framing and encryption - https://bthome.io/format/
+//
+// Service data on UUID 0xFCD2: [device info, objects...], objects sorted by object ID
+// Encrypted frames: [device info, AES-CCM ciphertext, counter u32, MIC u32]
+//   nonce = MAC address (most significant byte first) + UUID (little-endian) + device info + counter,
+//   4 byte MIC, no associated data
+use heapless::Vec;
+
+pub const BTHOME_UUID: u16 = 0xFCD2;
+
+pub const DEVICE_INFO_V2: u8 = 0x40;        // BTHome version 2 in bits 5-7
+pub const DEVICE_INFO_ENCRYPTED: u8 = 0x01;
+
+// Object IDs
+pub const OBJ_PACKET_ID: u8 = 0x00;         // uint8
+pub const OBJ_BATTERY: u8 = 0x01;           // uint8, %
+pub const OBJ_TEMPERATURE: u8 = 0x02;       // sint16, 0.01 degC
+pub const OBJ_HUMIDITY: u8 = 0x03;          // uint16, 0.01 %RH
+pub const OBJ_PRESSURE: u8 = 0x04;          // uint24, 0.01 hPa
+
+pub const MAX_OBJECTS_LEN: usize = 2 + 2 + 3 + 3 + 4;
+pub const MAX_SERVICE_DATA_LEN: usize = 1 + MAX_OBJECTS_LEN + 4 + 4;
+
+const MIC_LEN: usize = 4;
+
+// AES-128 in one direction, all CCM needs
+pub trait BlockCipher {
+    type Error;
+
+    fn encrypt_block(&self, block: &mut [u8; 16]) -> Result<(), Self::Error>;
+}
+
+pub fn service_data(objects: &[u8]) -> Vec<u8, MAX_SERVICE_DATA_LEN> {
+    let mut data = Vec::new();
+    let _ = data.push(DEVICE_INFO_V2);
+    let _ = data.extend_from_slice(objects);
+    data
+}
+
+// mac as displayed, e.g. [0xC0, 0x11, ...] for C0:11:...
+// A failed block encryption fails the whole frame, half encrypted data must never go on air
+pub fn encrypted_service_data<C: BlockCipher>(
+    objects: &[u8],
+    cipher: &C,
+    mac: [u8; 6],
+    counter: u32,
+) -> Result<Vec<u8, MAX_SERVICE_DATA_LEN>, C::Error> {
+    let device_info = DEVICE_INFO_V2 | DEVICE_INFO_ENCRYPTED;
+
+    let mut nonce = [0u8; 13];
+    nonce[0..6].copy_from_slice(&mac);
+    nonce[6..8].copy_from_slice(&BTHOME_UUID.to_le_bytes());
+    nonce[8] = device_info;
+    nonce[9..13].copy_from_slice(&counter.to_le_bytes());
+
+    let mut data: Vec<u8, MAX_SERVICE_DATA_LEN> = Vec::new();
+    let _ = data.push(device_info);
+    let _ = data.extend_from_slice(objects);
+    let mic = ccm_encrypt(cipher, &nonce, &mut data[1..])?;
+    let _ = data.extend_from_slice(&counter.to_le_bytes());
+    let _ = data.extend_from_slice(&mic);
+    Ok(data)
+}
+
+// AES-CCM (RFC 3610) with a 13 byte nonce, 4 byte MIC and no associated data, encrypts payload in place
+// Payloads are at most a few blocks, the length field is 2 bytes
+fn ccm_encrypt<C: BlockCipher>(cipher: &C, nonce: &[u8; 13], payload: &mut [u8]) -> Result<[u8; MIC_LEN], C::Error> {
+    const L: usize = 2;
+    let flags_mac = ((((MIC_LEN - 2) / 2) << 3) | (L - 1)) as u8;
+    let flags_ctr = (L - 1) as u8;
+
+    // CBC-MAC over B0 and the zero padded plaintext
+    let mut mac = [0u8; 16];
+    mac[0] = flags_mac;
+    mac[1..14].copy_from_slice(nonce);
+    mac[14..16].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    cipher.encrypt_block(&mut mac)?;
+    for chunk in payload.chunks(16) {
+        for (m, p) in mac.iter_mut().zip(chunk) {
+            *m ^= p;
+        }
+        cipher.encrypt_block(&mut mac)?;
+    }
+
+    // CTR - counter block 0 masks the MIC, 1.. the payload
+    let ctr_block = |i: u16| {
+        let mut block = [0u8; 16];
+        block[0] = flags_ctr;
+        block[1..14].copy_from_slice(nonce);
+        block[14..16].copy_from_slice(&i.to_be_bytes());
+        cipher.encrypt_block(&mut block).map(|()| block)
+    };
+    for (i, chunk) in payload.chunks_mut(16).enumerate() {
+        let stream = ctr_block(i as u16 + 1)?;
+        for (p, s) in chunk.iter_mut().zip(stream) {
+            *p ^= s;
+        }
+    }
+
+    let s0 = ctr_block(0)?;
+    let mut mic = [0u8; MIC_LEN];
+    for ((m, t), s) in mic.iter_mut().zip(mac).zip(s0) {
+        *m = t ^ s;
+    }
+    Ok(mic)
+}
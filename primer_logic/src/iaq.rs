@@ -0,0 +1,112 @@
+// Indoor air quality estimate from BME680 gas resistance and humidity
+// Pure f32 logic, nothing here touches hardware so it can be fed recorded traces - see tests/iaq.rs
+//
+// Score follows the usual open BME680 approach:
+//  - a burn-in period averages gas resistance to get a clean-air baseline
+//  - the baseline then follows clean air up quickly and drifts down slowly
+//  - 75% of the score comes from gas resistance relative to baseline,
+//    25% from how far humidity is from the 40 %RH ideal
+//  - air quality 0-100 (100 = best) is mapped to IAQ 0-500 (0 = best)
+
+const HUM_BASELINE: f32 = 40.0;         // %RH
+const HUM_WEIGHTING: f32 = 0.25;        // Share of the score coming from humidity
+const BASELINE_RISE: f32 = 0.1;         // Follow cleaner air quickly
+const BASELINE_DECAY: f32 = 0.001;      // Forget old clean air slowly
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum IaqAccuracy {
+    Stabilizing = 0,    // Burn-in still running
+    Low = 1,            // Baseline only from burn-in
+    Medium = 2,
+    High = 3,           // Baseline has tracked for several burn-in periods
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IaqReading {
+    pub iaq: u16,
+    pub accuracy: IaqAccuracy,
+}
+
+pub struct IaqEstimator {
+    burn_in_samples: u32,
+    samples: u32,
+    gas_baseline: f32,
+}
+
+impl IaqEstimator {
+
+    // burn_in_samples - number of gas readings averaged before the baseline is trusted
+    pub const fn new(burn_in_samples: u32) -> Self {
+        IaqEstimator {
+            burn_in_samples: if burn_in_samples == 0 { 1 } else { burn_in_samples },
+            samples: 0,
+            gas_baseline: 0.0,
+        }
+    }
+
+    // gas_ohm - compensated gas resistance
+    // humidity - relative humidity in 0.001 %RH
+    pub fn update(&mut self, gas_ohm: u32, humidity: u32) -> IaqReading {
+        let gas = gas_ohm as f32;
+        self.samples = self.samples.saturating_add(1);
+
+        if self.samples <= self.burn_in_samples {
+            // Running mean during burn-in
+            self.gas_baseline += (gas - self.gas_baseline) / self.samples as f32;
+        } else if gas > self.gas_baseline {
+            self.gas_baseline += (gas - self.gas_baseline) * BASELINE_RISE;
+        } else {
+            self.gas_baseline += (gas - self.gas_baseline) * BASELINE_DECAY;
+        }
+
+        IaqReading {
+            iaq: iaq_score(gas, self.gas_baseline, humidity as f32 / 1000.0),
+            accuracy: self.accuracy(),
+        }
+    }
+
+    pub fn accuracy(&self) -> IaqAccuracy {
+        let periods = self.samples / self.burn_in_samples;
+        match periods {
+            0 => IaqAccuracy::Stabilizing,
+            1 => IaqAccuracy::Low,
+            2..=3 => IaqAccuracy::Medium,
+            _ => IaqAccuracy::High,
+        }
+    }
+
+    pub fn gas_baseline(&self) -> u32 {
+        self.gas_baseline as u32
+    }
+
+    // Start over, e.g. after the sensor was re-initialized
+    pub fn reset(&mut self) {
+        self.samples = 0;
+        self.gas_baseline = 0.0;
+    }
+}
+
+// IAQ 0-500 from gas resistance, its clean-air baseline and humidity in %RH
+pub fn iaq_score(gas: f32, gas_baseline: f32, humidity: f32) -> u16 {
+    let hum_max_score = HUM_WEIGHTING * 100.0;
+    let gas_max_score = 100.0 - hum_max_score;
+
+    let hum_offset = humidity - HUM_BASELINE;
+    let hum_score = if hum_offset > 0.0 {
+        (100.0 - HUM_BASELINE - hum_offset) / (100.0 - HUM_BASELINE) * hum_max_score
+    } else {
+        (HUM_BASELINE + hum_offset) / HUM_BASELINE * hum_max_score
+    };
+
+    let gas_score = if gas_baseline > 0.0 && gas < gas_baseline {
+        gas / gas_baseline * gas_max_score
+    } else {
+        gas_max_score
+    };
+
+    let air_quality = (hum_score + gas_score).clamp(0.0, 100.0);
+    ((100.0 - air_quality) * 5.0 + 0.5) as u16
+}
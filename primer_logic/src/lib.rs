@@ -0,0 +1,6 @@
+#![no_std]
+
+// Pure logic used by nrf52_rust_primer, re-exported under its system module
+pub mod iaq;
+pub mod advertising;
+pub mod bthome;
@@ -0,0 +1,134 @@
+// AdvertisementBuilder payload layout and limits
+
+use primer_logic::advertising::*;
+
+const SENSOR_SERVICE_UUID: u128 = 0x9e7312e0_2354_11eb_9f10_fbc30a62cf38;
+
+#[test]
+fn fields_in_call_order() {
+    let adv = AdvertisementBuilder::new(AdvLimit::Legacy)
+        .flags(FLAGS_GENERAL)
+        .manufacturer_data(COMPANY_ID, &[1, 2, 3])
+        .build()
+        .unwrap();
+
+    assert_eq!(adv.adv_data.as_slice(), &[2, AD_FLAGS, FLAGS_GENERAL, 6, AD_MANUFACTURER_DATA, 0xFF, 0xFF, 1, 2, 3]);
+    assert!(adv.scan_data.is_empty());
+}
+
+#[test]
+fn legacy_payload_fills_to_31_bytes() {
+    // Flags 3 bytes + manufacturer data 2 + 2 + 24 bytes = 31
+    let adv = AdvertisementBuilder::new(AdvLimit::Legacy)
+        .manufacturer_data(COMPANY_ID, &[0; 24])
+        .flags(FLAGS_GENERAL)
+        .build()
+        .unwrap();
+    assert_eq!(adv.adv_data.len(), LEGACY_ADV_LEN);
+}
+
+#[test]
+fn legacy_overflow() {
+    // One byte past 31
+    let result = AdvertisementBuilder::new(AdvLimit::Legacy)
+        .flags(FLAGS_GENERAL)
+        .manufacturer_data(COMPANY_ID, &[0; 25])
+        .build();
+    assert_eq!(result, Err(AdvertisementError::TooLong));
+
+    // The same data fits an extended advertisement
+    let result = AdvertisementBuilder::new(AdvLimit::Extended)
+        .flags(FLAGS_GENERAL)
+        .manufacturer_data(COMPANY_ID, &[0; 25])
+        .build();
+    assert!(result.is_ok());
+}
+
+#[test]
+fn first_error_wins() {
+    let result = AdvertisementBuilder::new(AdvLimit::Legacy)
+        .services_16(&[], true)
+        .manufacturer_data(COMPANY_ID, &[0; 40])
+        .build();
+    assert_eq!(result, Err(AdvertisementError::Empty));
+}
+
+#[test]
+fn empty_fields() {
+    let build = |builder: AdvertisementBuilder| builder.build();
+    assert_eq!(build(AdvertisementBuilder::new(AdvLimit::Legacy).services_16(&[], true)), Err(AdvertisementError::Empty));
+    assert_eq!(build(AdvertisementBuilder::new(AdvLimit::Legacy).services_128(&[], false)), Err(AdvertisementError::Empty));
+    assert_eq!(build(AdvertisementBuilder::new(AdvLimit::Legacy).short_name("")), Err(AdvertisementError::Empty));
+
+    // No room left for a single character
+    let full = AdvertisementBuilder::new(AdvLimit::Legacy).manufacturer_data(COMPANY_ID, &[0; 25]);
+    assert_eq!(full.name("nRF52 Sensor").build(), Err(AdvertisementError::Empty));
+}
+
+#[test]
+fn name_fits_complete() {
+    let adv = AdvertisementBuilder::new(AdvLimit::Legacy).flags(FLAGS_GENERAL).name("nRF52 Sensor").build().unwrap();
+    assert_eq!(&adv.adv_data[3..5], &[13, AD_COMPLETE_NAME]);
+    assert_eq!(&adv.adv_data[5..], b"nRF52 Sensor");
+}
+
+#[test]
+fn name_shortened_to_remaining_space() {
+    // 3 + 16 bytes used, 12 left = 10 name bytes
+    let adv = AdvertisementBuilder::new(AdvLimit::Legacy)
+        .flags(FLAGS_GENERAL)
+        .manufacturer_data(COMPANY_ID, &[0; 12])
+        .name("nRF52 Sensor")
+        .build()
+        .unwrap();
+    assert_eq!(adv.adv_data.len(), LEGACY_ADV_LEN);
+    assert_eq!(&adv.adv_data[19..21], &[11, AD_SHORT_NAME]);
+    assert_eq!(&adv.adv_data[21..], b"nRF52 Sens");
+}
+
+#[test]
+fn name_shortened_on_char_boundary() {
+    // 10 name bytes available, the 2 byte '°' sits at bytes 9..11 and is dropped whole
+    let adv = AdvertisementBuilder::new(AdvLimit::Legacy)
+        .flags(FLAGS_GENERAL)
+        .manufacturer_data(COMPANY_ID, &[0; 12])
+        .name("Sensor ø°C")
+        .build()
+        .unwrap();
+    let name = core::str::from_utf8(&adv.adv_data[21..]).unwrap();
+    assert_eq!(name, "Sensor ø");
+    assert_eq!(adv.adv_data[19], name.len() as u8 + 1);
+
+    // 3 byte characters, 10 bytes only hold three of them
+    let adv = AdvertisementBuilder::new(AdvLimit::Legacy)
+        .flags(FLAGS_GENERAL)
+        .manufacturer_data(COMPANY_ID, &[0; 12])
+        .name("温度传感器")
+        .build()
+        .unwrap();
+    assert_eq!(core::str::from_utf8(&adv.adv_data[21..]).unwrap(), "温度传");
+}
+
+#[test]
+fn scan_response_has_its_own_legacy_limit() {
+    let adv = AdvertisementBuilder::new(AdvLimit::Legacy)
+        .flags(FLAGS_GENERAL)
+        .manufacturer_data(COMPANY_ID, &[0; 24])
+        .scan_response()
+        .services_128(&[SENSOR_SERVICE_UUID], true)
+        .name("nRF52 Sensor")
+        .build()
+        .unwrap();
+    assert_eq!(adv.adv_data.len(), LEGACY_ADV_LEN);
+    assert_eq!(&adv.scan_data[..2], &[17, AD_COMPLETE_128]);
+    assert_eq!(&adv.scan_data[2..18], &SENSOR_SERVICE_UUID.to_le_bytes());
+    assert_eq!(adv.scan_data.len(), LEGACY_ADV_LEN);
+    assert_eq!(adv.scan_data[19], AD_SHORT_NAME);
+
+    // Extended advertising doesn't raise the scan response limit
+    let result = AdvertisementBuilder::new(AdvLimit::Extended)
+        .scan_response()
+        .services_128(&[SENSOR_SERVICE_UUID, SENSOR_SERVICE_UUID], true)
+        .build();
+    assert_eq!(result, Err(AdvertisementError::TooLong));
+}
@@ -0,0 +1,67 @@
+// BTHome framing and AES-CCM against the encryption example on bthome.io
+
+use aes::Aes128;
+use aes::cipher::{BlockEncrypt, KeyInit};
+
+use primer_logic::bthome::*;
+
+struct SoftAes(Aes128);
+
+impl BlockCipher for SoftAes {
+    type Error = ();
+
+    fn encrypt_block(&self, block: &mut [u8; 16]) -> Result<(), ()> {
+        self.0.encrypt_block(block.into());
+        Ok(())
+    }
+}
+
+// Fails the nth block, counting from 0
+struct FailingAes(core::cell::Cell<u32>);
+
+impl BlockCipher for FailingAes {
+    type Error = ();
+
+    fn encrypt_block(&self, _block: &mut [u8; 16]) -> Result<(), ()> {
+        let left = self.0.get();
+        self.0.set(left.wrapping_sub(1));
+        if left == 0 { Err(()) } else { Ok(()) }
+    }
+}
+
+const KEY: [u8; 16] = [0x23, 0x1d, 0x39, 0xc1, 0xd7, 0xcc, 0x1a, 0xb1, 0xae, 0xe2, 0x24, 0xcd, 0x09, 0x6d, 0xb9, 0x32];
+const MAC: [u8; 6] = [0x54, 0x48, 0xE6, 0x8F, 0x80, 0xA5];
+const COUNTER: u32 = 0x3322_1100;
+
+// Temperature 25.06 degC, humidity 50.55 %RH
+const OBJECTS: [u8; 6] = [0x02, 0xca, 0x09, 0x03, 0xbf, 0x13];
+
+#[test]
+fn unencrypted_frame() {
+    let data = service_data(&OBJECTS);
+    assert_eq!(data.as_slice(), &[0x40, 0x02, 0xca, 0x09, 0x03, 0xbf, 0x13]);
+}
+
+#[test]
+fn bthome_io_encryption_example() {
+    let cipher = SoftAes(Aes128::new(&KEY.into()));
+    let data = encrypted_service_data(&OBJECTS, &cipher, MAC, COUNTER).unwrap();
+    assert_eq!(
+        data.as_slice(),
+        &[
+            0x41,                                   // device info, encrypted
+            0xa4, 0x72, 0x66, 0xc9, 0x5f, 0x73,     // ciphertext
+            0x00, 0x11, 0x22, 0x33,                 // counter, little-endian
+            0x78, 0x23, 0x72, 0x14,                 // MIC
+        ]
+    );
+}
+
+#[test]
+fn cipher_error_fails_the_frame() {
+    // One block each for B0, the payload MAC, the payload keystream and the MIC mask
+    for failing in 0..4 {
+        let cipher = FailingAes(core::cell::Cell::new(failing));
+        assert_eq!(encrypted_service_data(&OBJECTS, &cipher, MAC, COUNTER), Err(()));
+    }
+}
@@ -0,0 +1,97 @@
+// IAQ estimator against known inputs and synthetic sensor traces
+// Traces are gas_ohm,humidity_milli_pct per line, '#' lines are comments
+
+use primer_logic::iaq::{IaqAccuracy, IaqEstimator, iaq_score};
+
+const WARMUP: &str = include_str!("traces/warmup.csv");
+const VOC_EVENT: &str = include_str!("traces/voc_event.csv");
+
+fn samples(trace: &str) -> impl Iterator<Item = (u32, u32)> + '_ {
+    trace.lines().filter(|line| !line.starts_with('#') && !line.is_empty()).map(|line| {
+        let (gas, humidity) = line.split_once(',').unwrap();
+        (gas.trim().parse().unwrap(), humidity.trim().parse().unwrap())
+    })
+}
+
+#[test]
+fn burn_in_baseline_is_the_mean() {
+    let mut est = IaqEstimator::new(4);
+    for gas in [100, 200, 300, 400] {
+        est.update(gas, 40_000);
+    }
+    assert_eq!(est.gas_baseline(), 250);
+}
+
+#[test]
+fn accuracy_steps_with_burn_in_periods() {
+    let mut est = IaqEstimator::new(10);
+    for n in 1..=45 {
+        let accuracy = est.update(100_000, 40_000).accuracy;
+        let expected = match n {
+            1..=9 => IaqAccuracy::Stabilizing,
+            10..=19 => IaqAccuracy::Low,
+            20..=39 => IaqAccuracy::Medium,
+            _ => IaqAccuracy::High,
+        };
+        assert_eq!(accuracy, expected, "sample {}", n);
+    }
+
+    est.reset();
+    assert_eq!(est.accuracy(), IaqAccuracy::Stabilizing);
+    assert_eq!(est.gas_baseline(), 0);
+}
+
+#[test]
+fn zero_burn_in_is_one_sample() {
+    let mut est = IaqEstimator::new(0);
+    assert_eq!(est.update(100_000, 40_000).accuracy, IaqAccuracy::Low);
+}
+
+#[test]
+fn humidity_compensation() {
+    // Gas at baseline, only humidity moves the score
+    assert_eq!(iaq_score(100_000.0, 100_000.0, 40.0), 0);
+    assert_eq!(iaq_score(100_000.0, 100_000.0, 70.0), 63);
+    assert_eq!(iaq_score(100_000.0, 100_000.0, 20.0), 63);
+    assert_eq!(iaq_score(100_000.0, 100_000.0, 0.0), 125);
+    assert_eq!(iaq_score(100_000.0, 100_000.0, 100.0), 125);
+
+    // Cleaner than baseline scores the same as baseline
+    assert_eq!(iaq_score(150_000.0, 100_000.0, 40.0), 0);
+    // Half the baseline resistance costs half the gas share
+    assert_eq!(iaq_score(50_000.0, 100_000.0, 40.0), 188);
+}
+
+#[test]
+fn warmup_trace_settles() {
+    let mut est = IaqEstimator::new(300);
+    let mut last = None;
+    for (gas, humidity) in samples(WARMUP) {
+        last = Some(est.update(gas, humidity));
+    }
+    let last = last.unwrap();
+
+    // Burn-in mean is dragged down by the cold heater, clean air pulls it back up
+    let baseline = est.gas_baseline();
+    assert!((114_000..=126_000).contains(&baseline), "baseline {}", baseline);
+    assert!(last.iaq < 50, "iaq {}", last.iaq);
+    assert_eq!(last.accuracy, IaqAccuracy::Medium);
+}
+
+#[test]
+fn voc_event_raises_and_recovers() {
+    let mut est = IaqEstimator::new(100);
+    let readings: Vec<_> = samples(VOC_EVENT).map(|(gas, humidity)| (est.update(gas, humidity), est.gas_baseline())).collect();
+    assert_eq!(readings.len(), 600);
+
+    let before = readings[299].0.iaq;
+    let peak = readings[300..360].iter().map(|(r, _)| r.iaq).max().unwrap();
+    let after = readings[599].0.iaq;
+    assert!(before < 50, "before {}", before);
+    assert!(peak > 150, "peak {}", peak);
+    assert!(after < 50, "after {}", after);
+
+    // A short event must not drag the clean-air baseline down with it
+    let min_baseline = readings[100..].iter().map(|&(_, b)| b).min().unwrap();
+    assert!(min_baseline > 100_000, "baseline {}", min_baseline);
+}
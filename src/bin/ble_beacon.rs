@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+
+use nrf52_rust_primer::d_ble::nrf_ble::BLEWrapper;
+use nrf52_rust_primer::embassy_hal::saadc::{ChannelConfig, VddInput};
+use nrf52_rust_primer::system::battery::BatteryConfig;
+use nrf52_rust_primer::system::sensor_updates::{self, battery_update, bme_update};
+use nrf52_rust_primer::system::beacons::{BeaconFrame, BeaconSlot, EddystoneUid, EddystoneUrl, IBeacon, beacon_rotation};
+
+use nrf52_rust_primer::d_info;
+
+// Example identifiers - swap for your own namespace and proximity UUID
+const UID: EddystoneUid = EddystoneUid {
+    namespace: [0x8b, 0x0c, 0xa7, 0x50, 0xe7, 0xa7, 0x4e, 0x14, 0xbd, 0x99],
+    instance: [0x00, 0x00, 0x00, 0x00, 0x00, 0x01],
+    tx_power: -20,
+};
+
+const URL: EddystoneUrl = EddystoneUrl {
+    url: "https://example.com/",
+    tx_power: -20,
+};
+
+const IBEACON: IBeacon = IBeacon {
+    uuid: 0xe2c56db5_dffb_48d2_b060_d0f5a71096e0,
+    major: 1,
+    minor: 1,
+    measured_power: -59,
+};
+
+// Telemetry every fifth frame, each frame on air for 2 s
+static SCHEDULE: [BeaconSlot; 5] = [
+    BeaconSlot { frame: BeaconFrame::EddystoneUid(UID), dwell_ms: 2000 },
+    BeaconSlot { frame: BeaconFrame::EddystoneUrl(URL), dwell_ms: 2000 },
+    BeaconSlot { frame: BeaconFrame::IBeacon(IBEACON), dwell_ms: 2000 },
+    BeaconSlot { frame: BeaconFrame::EddystoneTlm, dwell_ms: 2000 },
+    BeaconSlot { frame: BeaconFrame::IBeacon(IBEACON), dwell_ms: 2000 },
+];
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    d_info!("Main script starting!");
+
+    // Very finicky - HAL interrupts have to be given lower priority than softdeivce
+    // this block needs to come before SoftDevice is enabled
+    let p = sensor_updates::start_peripherals();
+
+    // Start BLE subsystem - no GATT server, beacons are non-connectable
+    let ble = BLEWrapper::start(spawner, None, None, None).await;
+
+    // Return and print BLE address
+    ble.get_ble_address().unwrap();
+
+    // Temperature and battery voltage for the TLM frame
+    let i2c_mutex_wrapper = sensor_updates::start_i2c(p.P0_26, p.P0_27, p.TWISPI0);
+    spawner.spawn(bme_update(i2c_mutex_wrapper)).unwrap();
+
+    // Coin cell straight on VDD
+    let saadc = sensor_updates::start_saadc(p.SAADC, ChannelConfig::single_ended(VddInput));
+    spawner.spawn(battery_update(saadc, BatteryConfig::coin_cell_vdd())).unwrap();
+
+    // Advertise every 100 ms, the frame changes per the schedule
+    spawner.spawn(beacon_rotation(&SCHEDULE, 100)).unwrap();
+
+    d_info!("Main script complete");
+}
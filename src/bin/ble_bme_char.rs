@@ -2,15 +2,32 @@
 #![no_main]
 
 use embassy_executor::Spawner;
-use embassy_futures::select::{select, Either};
-use embassy_futures::join::join;
+use embassy_time::Timer;
+use embassy_futures::select::{select, select3, select4, Either};
 
 use nrf52_rust_primer::d_ble::nrf_ble::BLEWrapper;
 use nrf52_rust_primer::system::ble_services::{self, *};
-use nrf52_rust_primer::system::sensor_updates::{self, bme_update};
-use nrf52_rust_primer::system::state::{TEMP_VAL, PRESSURE_VAL};
+use nrf52_rust_primer::embassy_hal::saadc::{ChannelConfig, VddInput};
+use nrf52_rust_primer::d_peripherals::chip_implementations::I2CMutexWrapper;
+use nrf52_rust_primer::system::battery::BatteryConfig;
+use nrf52_rust_primer::system::sensor_updates::{self, battery_update, bme_update, tsl_update};
+use nrf52_rust_primer::system::update_policy::UpdatePolicy;
+use nrf52_rust_primer::system::storage::{self, BOND_STORE_START, CONFIG_STORE_START, HISTORY_PAGES, HISTORY_START};
+use nrf52_rust_primer::system::bonding::{self, Bonder, PairingMode, bond_persist};
+use nrf52_rust_primer::system::advertising::{self, AdvLimit, Advertisement, AdvertisementBuilder, COMPANY_ID, FLAGS_GENERAL};
+use nrf52_rust_primer::system::broadcaster::Broadcaster;
+use nrf52_rust_primer::system::state;
+use nrf52_rust_primer::system::history::{self, HistoryLog, history_log};
+use nrf52_rust_primer::system::config_store::{self, ConfigStore, config_persist};
 
-use nrf52_rust_primer::d_info;
+use nrf52_rust_primer::{d_info, warn};
+
+use static_cell::StaticCell;
+
+static BONDER: StaticCell<Bonder> = StaticCell::new();
+
+const DEVICE_NAME: &str = "nRF52 Sensor";
+const ADV_REFRESH_MS: u64 = 10_000;
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
@@ -26,26 +43,132 @@ async fn main(spawner: Spawner) {
     // Return and print BLE address
     ble.get_ble_address().unwrap();
 
+    // Firmware / hardware metadata for the Device Information Service
+    ble_services::init_device_info(&server);
+
+    // Restore the saved runtime config from flash and keep saving changes
+    // The boot count goes on history records, 0 if the store is unavailable
+    let flash = storage::init_flash(storage::softdevice_flash());
+    let mut boot = 0;
+    match ConfigStore::mount(flash, CONFIG_STORE_START).await {
+        Ok(mut store) => {
+            if let Err(e) = config_store::restore_runtime_config(&mut store).await {
+                warn!("Runtime config not restored: {:?}", e);
+            }
+            match config_store::next_boot(&mut store).await {
+                Ok(count) => boot = count,
+                Err(e) => warn!("Boot not counted: {:?}", e),
+            }
+            spawner.spawn(config_persist(store)).unwrap();
+        }
+        Err(e) => warn!("Config store unavailable: {:?}", e),
+    }
+
+    // Bonds from earlier pairings, new ones are saved as they happen
+    let bonder = BONDER.init(Bonder::new(PairingMode::JustWorks));
+    match ConfigStore::mount(flash, BOND_STORE_START).await {
+        Ok(mut store) => {
+            if let Err(e) = bonder.restore(&mut store).await {
+                warn!("Bonds not restored: {:?}", e);
+            }
+            spawner.spawn(bond_persist(bonder, store)).unwrap();
+        }
+        Err(e) => warn!("Bond store unavailable: {:?}", e),
+    }
+
+    // Sensor history in flash, downloaded through the history service
+    let history_period_ms: u64 = 60_000;
+    let history = match HistoryLog::mount(flash, HISTORY_START, HISTORY_PAGES, boot).await {
+        Ok(log) => {
+            let history = history::init_history(log);
+            spawner.spawn(history_log(history, history_period_ms)).unwrap();
+            Some(history)
+        }
+        Err(e) => {
+            warn!("History log unavailable: {:?}", e);
+            None
+        }
+    };
+
+    // Sample / notify periods and oversampling, writable through the config service
+    ble_services::init_config(&server);
+
     // Initialize I2C Bus
     let i2c_mutex_wrapper = sensor_updates::start_i2c(p.P0_26, p.P0_27, p.TWISPI0);
 
     // Spawn bme680 task (runs concurrently in background)
     d_info!("BME680 Read starting...");
-    let bme_delay_ms: u64 = 500;    // Frequency at which to read the sensor
-    let bme_update_ms: u64 = 1000;  // Frequency at which to update the characteristic
-    spawner.spawn(bme_update(i2c_mutex_wrapper, bme_delay_ms)).unwrap();
+    let tsl_bus = I2CMutexWrapper(i2c_mutex_wrapper.0);   // Same bus, shared through the mutex
+    spawner.spawn(bme_update(i2c_mutex_wrapper)).unwrap();
+
+    // Spawn tsl2591 task (runs concurrently in background)
+    d_info!("TSL2591 Read starting...");
+    let tsl_delay_ms: u64 = 1000;
+    spawner.spawn(tsl_update(tsl_bus, tsl_delay_ms)).unwrap();
+
+    // Spawn battery task - coin cell straight on VDD
+    d_info!("Battery monitor starting...");
+    let saadc = sensor_updates::start_saadc(p.SAADC, ChannelConfig::single_ended(VddInput));
+    spawner.spawn(battery_update(saadc, BatteryConfig::coin_cell_vdd())).unwrap();
+
+    // Characteristics are only pushed when a value moves past its deadband
+    // A steady value is still pushed once per notify period
+    let on_change = |deadband: u32| UpdatePolicy::OnChange { deadband };
+    let temp_policy = on_change(1);           // 1 degC
+    let ess_temp_policy = on_change(10);      // 0.1 degC (0.01 degC units)
+    let pressure_policy = on_change(10);      // 10 Pa
+    let iaq_policy = on_change(5);
+    let lux_policy = on_change(1000);         // 10 lux
+    let ess_pressure_policy = on_change(100); // 10 Pa (0.1 Pa units)
+    let humidity_policy = on_change(50);      // 0.5 %RH (0.01 %RH units)
+    let battery_policy = on_change(1);        // 1 %
+
+    // Refreshes the readings in the connectable advertising without restarting it
+    let mut broadcaster = Broadcaster::take();
 
     // This loop will iterate every time either the update_fur or gatt_fur runs (so only upon disconnect)
     loop {
 
-        // Advertise + wait for connection
-        let conn = ble.advertise(true).await.unwrap();
+        // Advertise + wait for connection, centrals can pair and bond
+        // The readings are swapped in every ADV_REFRESH_MS while advertising keeps running, so a central
+        // that is connecting is never cut off - on first boot it starts out empty
+        let adv = sensor_advertisement();
+        let refresh = async {
+            loop {
+                Timer::after_millis(ADV_REFRESH_MS).await;
+                let adv = sensor_advertisement();
+                if let Err(e) = broadcaster.update_running(&adv.adv_data, &adv.scan_data) {
+                    warn!("Advertising data not refreshed: {:?}", e);
+                }
+            }
+        };
+        let conn = match select(bonding::advertise(bonder, &adv.adv_data, &adv.scan_data), refresh).await {
+            Either::First(conn) => conn.unwrap(),
+            Either::Second(_) => unreachable!(),                            // Refresh loop never finishes
+        };
 
         // Code for updating service characteristic
-        // This joins multiple futures into 1
-        let update_characteristics = join(
-            ble_services::update_temperature(&server, &TEMP_VAL, bme_update_ms),
-            ble_services::update_pressure(&server, &PRESSURE_VAL, bme_update_ms),
+        // Runs every updater, finishes as soon as one of them fails
+        // Diagnostics and subscription restore never finish
+        let update_characteristics = select4(
+            select4(
+                ble_services::update_temperature(&server, &conn, temp_policy),
+                ble_services::update_pressure(&server, &conn, pressure_policy),
+                ble_services::update_iaq(&server, &conn, iaq_policy),
+                ble_services::update_illuminance(&server, &conn, lux_policy),
+            ),
+            select4(
+                ble_services::update_ess_temperature(&server, &conn, ess_temp_policy),
+                ble_services::update_ess_pressure(&server, &conn, ess_pressure_policy),
+                ble_services::update_ess_humidity(&server, &conn, humidity_policy),
+                ble_services::update_battery(&server, &conn, battery_policy),
+            ),
+            select3(
+                ble_services::update_sensor_status(&server, &conn),
+                ble_services::update_diagnostics(&server, 1000),
+                ble_services::restore_subscriptions(&server),
+            ),
+            ble_services::serve_history(&server, &conn, history),
         );
         
         // Run the GATT server on the connection. This returns when the connection gets disconnected.
@@ -54,7 +177,26 @@ async fn main(spawner: Spawner) {
         // These are both async functions
         match select(gatt_server_fut, update_characteristics).await {
             Either::First(e) => d_info!("Device disonnected: {:?}", e),     // If the first passed future finishes first
-            Either::Second(_) => {},                                            // If the second passed future finished first (is an infite loop, should never finish)
+            Either::Second(_) => {
+                // A notify failed with something other than out-of-buffers, the link is no good
+                // Drop it and go back to advertising
+                warn!("Characteristic updater failed, disconnecting");
+                if let Err(e) = conn.disconnect() {
+                    warn!("Disconnect failed: {:?}", e);
+                }
+            }
         };
     }
-}
\ No newline at end of file
+}
+
+// Latest readings in the manufacturer data, the sensor service UUID in the scan response
+fn sensor_advertisement() -> Advertisement {
+    AdvertisementBuilder::new(AdvLimit::Legacy)
+        .flags(FLAGS_GENERAL)
+        .manufacturer_data(COMPANY_ID, &advertising::sensor_data(&state::snapshot()))
+        .name(DEVICE_NAME)
+        .scan_response()
+        .services_128(&[SENSOR_SERVICE_UUID], true)
+        .build()
+        .unwrap()
+}
@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+
+use nrf52_rust_primer::d_ble::nrf_ble::BLEWrapper;
+use nrf52_rust_primer::embassy_hal::saadc::{ChannelConfig, VddInput};
+use nrf52_rust_primer::d_peripherals::chip_implementations::I2CMutexWrapper;
+use nrf52_rust_primer::system::battery::BatteryConfig;
+use nrf52_rust_primer::system::sensor_updates::{self, battery_update, bme_update, tsl_update};
+use nrf52_rust_primer::system::storage::{self, CONFIG_STORE_START};
+use nrf52_rust_primer::system::config_store::{self, ConfigStore};
+use nrf52_rust_primer::system::bthome::{BroadcastConfig, bthome_broadcast};
+
+use nrf52_rust_primer::{d_info, warn};
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    d_info!("Main script starting!");
+
+    // Very finicky - HAL interrupts have to be given lower priority than softdeivce
+    // this block needs to come before SoftDevice is enabled
+    let p = sensor_updates::start_peripherals();
+
+    // Start BLE subsystem - no GATT server, gateways only scan
+    let ble = BLEWrapper::start(spawner, None, None, None).await;
+
+    // Return and print BLE address
+    ble.get_ble_address().unwrap();
+
+    // Saved runtime config, the store then holds the broadcast key and encryption counter
+    // Unencrypted broadcasts don't need it, so a store that won't mount only costs the saved config
+    let flash = storage::init_flash(storage::softdevice_flash());
+    let store = match ConfigStore::mount(flash, CONFIG_STORE_START).await {
+        Ok(mut store) => {
+            if let Err(e) = config_store::restore_runtime_config(&mut store).await {
+                warn!("Runtime config not restored: {:?}", e);
+            }
+            Some(store)
+        }
+        Err(e) => {
+            warn!("Config store unavailable: {:?}", e);
+            None
+        }
+    };
+
+    // Initialize I2C Bus
+    let i2c_mutex_wrapper = sensor_updates::start_i2c(p.P0_26, p.P0_27, p.TWISPI0);
+
+    // Spawn sensor tasks (run concurrently in background)
+    d_info!("Sensors starting...");
+    let tsl_bus = I2CMutexWrapper(i2c_mutex_wrapper.0);   // Same bus, shared through the mutex
+    spawner.spawn(bme_update(i2c_mutex_wrapper)).unwrap();
+    spawner.spawn(tsl_update(tsl_bus, 1000)).unwrap();
+
+    // Coin cell straight on VDD
+    let saadc = sensor_updates::start_saadc(p.SAADC, ChannelConfig::single_ended(VddInput));
+    spawner.spawn(battery_update(saadc, BatteryConfig::coin_cell_vdd())).unwrap();
+
+    // Readings re-encoded every 10 s, advertised every second in between
+    let broadcast = BroadcastConfig {
+        interval_ms: 1000,
+        update_ms: 10_000,
+        encrypted: false,       // true for AES-CCM, the key is logged at boot for the gateway
+    };
+    spawner.spawn(bthome_broadcast(broadcast, store)).unwrap();
+
+    d_info!("Main script complete");
+}
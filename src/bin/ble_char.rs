@@ -27,6 +27,9 @@ async fn main(spawner: Spawner) {
     // Return and print BLE address
     ble.get_ble_address().unwrap();
 
+    // Firmware / hardware metadata for the Device Information Service
+    ble_services::init_device_info(&server);
+
     // This loop will iterate every time either the update_fur or gatt_fur runs (so only upon disconnect)
     loop {
 
@@ -0,0 +1,27 @@
+// TSL2591 light readout
+#![no_main]
+#![no_std]
+
+use embassy_executor::Spawner;
+use embassy_time::Timer;
+
+use nrf52_rust_primer::system::sensor_updates::{self, tsl_update};
+use nrf52_rust_primer::system::state;
+use nrf52_rust_primer::d_info;  // Logging
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = sensor_updates::start_peripherals();
+
+    // Initialize I2C Bus
+    let i2c_mutex_wrapper = sensor_updates::start_i2c(p.P0_26, p.P0_27, p.TWISPI0);
+
+    // Spawn tsl2591 task (runs concurrently in background)
+    d_info!("TSL2591 Read starting...");
+    spawner.spawn(tsl_update(i2c_mutex_wrapper, 1000)).unwrap();
+
+    loop {
+        d_info!("Lux x100: {}", state::snapshot().lux());   // None until the first reading
+        Timer::after_secs(5).await;
+    }
+}
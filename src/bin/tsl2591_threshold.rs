@@ -0,0 +1,27 @@
+// TSL2591 light readout driven by the threshold interrupt
+#![no_main]
+#![no_std]
+
+use embassy_executor::Spawner;
+use embassy_time::Timer;
+
+use nrf52_rust_primer::system::sensor_updates::{self, tsl_threshold_update};
+use nrf52_rust_primer::system::state;
+use nrf52_rust_primer::d_info;  // Logging
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let p = sensor_updates::start_peripherals();
+
+    // Initialize I2C Bus
+    let i2c_mutex_wrapper = sensor_updates::start_i2c(p.P0_26, p.P0_27, p.TWISPI0);
+
+    // Spawn tsl2591 task - INT on P0.28, wakes when light moves 10% away from the last reading
+    d_info!("TSL2591 threshold read starting...");
+    spawner.spawn(tsl_threshold_update(i2c_mutex_wrapper, p.P0_28.into(), 10)).unwrap();
+
+    loop {
+        d_info!("Lux x100: {}", state::snapshot().lux());   // None until the first reading
+        Timer::after_secs(5).await;
+    }
+}
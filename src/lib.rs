@@ -36,6 +36,22 @@ pub mod system {
     pub mod state;
     pub mod ble_services;
     pub mod sensor_updates;
+    pub mod sensor_error;
+    pub mod bme680_ext;
+    pub use primer_logic::iaq;
+    pub mod tsl2591_driver;
+    pub mod battery;
+    pub mod update_policy;
+    pub mod config;
+    pub mod storage;
+    pub mod config_store;
+    pub mod history;
+    pub mod diagnostics;
+    pub mod bonding;
+    pub mod advertising;
+    pub mod broadcaster;
+    pub mod bthome;
+    pub mod beacons;
 }
 
 // --- BLE Module Group ---
@@ -0,0 +1,27 @@
+// Advertising payloads - the builder lives in primer_logic so it can be tested on the host,
+// the sensor payload needs the snapshot and stays here
+pub use primer_logic::advertising::*;
+
+use crate::system::state::SensorSnapshot;
+
+// Manufacturer specific sensor payload, follows COMPANY_ID
+// [version u8, temperature i16 (0.01 degC), humidity u16 (0.01 %RH), pressure u32 (Pa), battery u8 (%)]
+// Channels that were never sampled are sent with all bits set, i16::MIN for temperature
+pub const SENSOR_DATA_VERSION: u8 = 1;
+pub const SENSOR_DATA_LEN: usize = 10;
+
+pub fn sensor_data(snapshot: &SensorSnapshot) -> [u8; SENSOR_DATA_LEN] {
+    let temperature = snapshot.temperature()
+        .map_or(i16::MIN, |t| t.clamp(i16::MIN as i32 + 1, i16::MAX as i32) as i16);
+    let humidity = snapshot.humidity().map_or(u16::MAX, |h| (h / 10).min(u16::MAX as u32 - 1) as u16);
+    let pressure = snapshot.pressure().unwrap_or(u32::MAX);
+    let battery = snapshot.battery_level().unwrap_or(u8::MAX);
+
+    let mut buf = [0u8; SENSOR_DATA_LEN];
+    buf[0] = SENSOR_DATA_VERSION;
+    buf[1..3].copy_from_slice(&temperature.to_le_bytes());
+    buf[3..5].copy_from_slice(&humidity.to_le_bytes());
+    buf[5..9].copy_from_slice(&pressure.to_le_bytes());
+    buf[9] = battery;
+    buf
+}
@@ -0,0 +1,101 @@
+// Battery voltage to state-of-charge conversion
+// Hardware sampling lives in sensor_updates, everything here is plain math
+
+// Piecewise-linear discharge curve, (mV, %) points ordered from full to empty
+pub struct DischargeCurve {
+    pub points: &'static [(u16, u8)],
+}
+
+// Single cell LiPo / Li-ion under light load
+pub const LIPO_CURVE: DischargeCurve = DischargeCurve {
+    points: &[
+        (4200, 100), (4100, 90), (4000, 80), (3900, 65), (3800, 50),
+        (3750, 40), (3700, 30), (3650, 20), (3600, 12), (3500, 5),
+        (3400, 2), (3300, 0),
+    ],
+};
+
+// CR2032 coin cell - flat plateau then a steep knee
+pub const COIN_CELL_CURVE: DischargeCurve = DischargeCurve {
+    points: &[
+        (3000, 100), (2900, 80), (2800, 60), (2700, 40), (2600, 20),
+        (2500, 10), (2300, 5), (2000, 0),
+    ],
+};
+
+impl DischargeCurve {
+
+    // Interpolated percentage for a cell voltage
+    pub fn percent(&self, mv: u16) -> u8 {
+        let (first, last) = match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return 0,
+        };
+        if mv >= first.0 {
+            return first.1;
+        }
+        if mv <= last.0 {
+            return last.1;
+        }
+
+        for pair in self.points.windows(2) {
+            let (hi_mv, hi_pct) = pair[0];
+            let (lo_mv, lo_pct) = pair[1];
+            if mv >= lo_mv {
+                let span_mv = (hi_mv - lo_mv) as u32;
+                let span_pct = (hi_pct - lo_pct) as u32;
+                return lo_pct + ((mv - lo_mv) as u32 * span_pct / span_mv.max(1)) as u8;
+            }
+        }
+
+        last.1
+    }
+}
+
+pub struct BatteryConfig {
+    pub curve: &'static DischargeCurve,
+    pub divider_num: u16,       // Battery voltage = measured * num / den
+    pub divider_den: u16,
+    pub sample_ms: u64,
+    pub smoothing_shift: u8,    // EMA weight 1 / 2^shift
+}
+
+impl BatteryConfig {
+    // Cell measured directly on VDD (coin cell, no regulator)
+    pub const fn coin_cell_vdd() -> Self {
+        BatteryConfig { curve: &COIN_CELL_CURVE, divider_num: 1, divider_den: 1, sample_ms: 10_000, smoothing_shift: 2 }
+    }
+
+    // LiPo on VDDH, sampled through the internal VDDH/5 input
+    pub const fn lipo_vddh() -> Self {
+        BatteryConfig { curve: &LIPO_CURVE, divider_num: 5, divider_den: 1, sample_ms: 10_000, smoothing_shift: 2 }
+    }
+}
+
+// SAADC sample to mV - gain 1/6 with the 0.6 V internal reference is 3.6 V full scale at 12 bit
+pub fn saadc_to_mv(raw: i16, config: &BatteryConfig) -> u16 {
+    let mv = raw.max(0) as u32 * 3600 / 4096;
+    (mv * config.divider_num as u32 / config.divider_den.max(1) as u32).min(u16::MAX as u32) as u16
+}
+
+// Exponential moving average so single noisy samples don't move the level
+pub struct BatteryFilter {
+    shift: u8,
+    avg_mv: Option<u32>,
+}
+
+impl BatteryFilter {
+    pub const fn new(shift: u8) -> Self {
+        BatteryFilter { shift, avg_mv: None }
+    }
+
+    pub fn update(&mut self, mv: u16) -> u16 {
+        let mv = mv as u32;
+        let avg = match self.avg_mv {
+            None => mv,
+            Some(avg) => avg - (avg >> self.shift) + (mv >> self.shift),
+        };
+        self.avg_mv = Some(avg);
+        avg as u16
+    }
+}
@@ -0,0 +1,262 @@
+// iBeacon and Eddystone beacon frames, and a scheduler that rotates between them
+//
+// iBeacon: manufacturer data for Apple (0x004C) - [0x02, 0x15, UUID, major, minor, measured power], big-endian
+// Eddystone: complete 16 bit service list 0xFEAA + service data on 0xFEAA, first byte is the frame type
+//   UID [0x00, tx power, namespace 10, instance 6, RFU 2]
+//   URL [0x10, tx power, scheme, encoded URL]
+//   TLM [0x20, version 0, battery mV, temperature 8.8, advertising count, uptime 0.1 s] - unencrypted, big-endian
+// https://github.com/google/eddystone/blob/master/protocol-specification.md
+//
+// Frame encoding is pure, beacon_rotation puts the frames on air through a Broadcaster.
+use core::sync::atomic::{AtomicU32, Ordering};
+use heapless::Vec;
+
+use embassy_time::{Instant, Timer};
+use nrf_softdevice::{raw, RawError};
+
+use crate::embassy_hal::interrupt;
+use crate::embassy_hal::interrupt::{InterruptExt, Priority};
+use crate::system::advertising::{AdvLimit, AdvertisementBuilder, AdvertisementError, FLAGS_GENERAL, LEGACY_ADV_LEN};
+use crate::system::broadcaster::Broadcaster;
+use crate::system::state::{self, SensorSnapshot};
+use crate::{d_info, warn};
+
+pub const APPLE_COMPANY_ID: u16 = 0x004C;
+pub const EDDYSTONE_UUID: u16 = 0xFEAA;
+
+const IBEACON_TYPE: [u8; 2] = [0x02, 0x15];     // Beacon type, remaining length
+
+const FRAME_UID: u8 = 0x00;
+const FRAME_URL: u8 = 0x10;
+const FRAME_TLM: u8 = 0x20;
+const TLM_VERSION: u8 = 0x00;
+
+const URL_MAX_LEN: usize = 17;
+const TLM_TEMPERATURE_MISSING: i16 = i16::MIN;  // 0x8000, "not supported"
+
+// Eddystone URL scheme prefixes, by code
+const URL_SCHEMES: [&str; 4] = ["http://www.", "https://www.", "http://", "https://"];
+
+// Eddystone URL expansions, by code - the ones with a slash come first so they win
+const URL_EXPANSIONS: [&str; 14] = [
+    ".com/", ".org/", ".edu/", ".net/", ".info/", ".biz/", ".gov/",
+    ".com", ".org", ".edu", ".net", ".info", ".biz", ".gov",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BeaconError {
+    UrlScheme,                          // URL doesn't start with http:// or https://
+    UrlTooLong,                         // More than 17 bytes after encoding
+    UrlCharacter,                       // Byte outside 0x21-0x7E, those are expansion codes or reserved
+    Advertisement(AdvertisementError),
+}
+
+impl From<AdvertisementError> for BeaconError {
+    fn from(e: AdvertisementError) -> Self {
+        BeaconError::Advertisement(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct IBeacon {
+    pub uuid: u128,                     // Proximity UUID as written
+    pub major: u16,
+    pub minor: u16,
+    pub measured_power: i8,             // RSSI at 1 m, dBm
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct EddystoneUid {
+    pub namespace: [u8; 10],
+    pub instance: [u8; 6],
+    pub tx_power: i8,                   // Calibrated RSSI at 0 m, dBm
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct EddystoneUrl {
+    pub url: &'static str,
+    pub tx_power: i8,                   // Calibrated RSSI at 0 m, dBm
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Telemetry {
+    pub battery_mv: u16,                // 0 if unknown
+    pub temperature: i16,               // degC in signed 8.8 fixed point, 0x8000 if unknown
+    pub adv_count: u32,                 // Advertising events since boot
+    pub uptime_ds: u32,                 // 0.1 s since boot
+}
+
+impl Telemetry {
+    pub fn from_snapshot(snapshot: &SensorSnapshot, adv_count: u32, uptime: Instant) -> Self {
+        Telemetry {
+            battery_mv: snapshot.battery_mv().unwrap_or(0),
+            temperature: snapshot.temperature().map_or(TLM_TEMPERATURE_MISSING, |t| {
+                // 0.01 degC to 1/256 degC
+                (t * 256 / 100).clamp(i16::MIN as i32 + 1, i16::MAX as i32) as i16
+            }),
+            adv_count,
+            uptime_ds: (uptime.as_millis() / 100) as u32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BeaconFrame {
+    IBeacon(IBeacon),
+    EddystoneUid(EddystoneUid),
+    EddystoneUrl(EddystoneUrl),
+    EddystoneTlm,                       // Filled from system::state when it goes on air
+}
+
+// One entry of a rotation, the frame stays on air for dwell_ms
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct BeaconSlot {
+    pub frame: BeaconFrame,
+    pub dwell_ms: u64,
+}
+
+pub fn ibeacon_adv(beacon: &IBeacon) -> Result<Vec<u8, LEGACY_ADV_LEN>, BeaconError> {
+    let mut data = [0u8; 23];
+    data[0..2].copy_from_slice(&IBEACON_TYPE);
+    data[2..18].copy_from_slice(&beacon.uuid.to_be_bytes());
+    data[18..20].copy_from_slice(&beacon.major.to_be_bytes());
+    data[20..22].copy_from_slice(&beacon.minor.to_be_bytes());
+    data[22] = beacon.measured_power as u8;
+
+    let adv = AdvertisementBuilder::new(AdvLimit::Legacy)
+        .flags(FLAGS_GENERAL)
+        .manufacturer_data(APPLE_COMPANY_ID, &data)
+        .build()?;
+    Ok(Vec::from_slice(&adv.adv_data).unwrap_or_default())
+}
+
+pub fn eddystone_uid_adv(uid: &EddystoneUid) -> Result<Vec<u8, LEGACY_ADV_LEN>, BeaconError> {
+    let mut data = [0u8; 20];
+    data[0] = FRAME_UID;
+    data[1] = uid.tx_power as u8;
+    data[2..12].copy_from_slice(&uid.namespace);
+    data[12..18].copy_from_slice(&uid.instance);
+    eddystone_adv(&data)
+}
+
+pub fn eddystone_url_adv(url: &EddystoneUrl) -> Result<Vec<u8, LEGACY_ADV_LEN>, BeaconError> {
+    let mut data: Vec<u8, { 3 + URL_MAX_LEN }> = Vec::new();
+    let _ = data.extend_from_slice(&[FRAME_URL, url.tx_power as u8]);
+    let _ = data.extend_from_slice(&encode_url(url.url)?);
+    eddystone_adv(&data)
+}
+
+pub fn eddystone_tlm_adv(tlm: &Telemetry) -> Result<Vec<u8, LEGACY_ADV_LEN>, BeaconError> {
+    let mut data = [0u8; 14];
+    data[0] = FRAME_TLM;
+    data[1] = TLM_VERSION;
+    data[2..4].copy_from_slice(&tlm.battery_mv.to_be_bytes());
+    data[4..6].copy_from_slice(&tlm.temperature.to_be_bytes());
+    data[6..10].copy_from_slice(&tlm.adv_count.to_be_bytes());
+    data[10..14].copy_from_slice(&tlm.uptime_ds.to_be_bytes());
+    eddystone_adv(&data)
+}
+
+fn eddystone_adv(frame: &[u8]) -> Result<Vec<u8, LEGACY_ADV_LEN>, BeaconError> {
+    let adv = AdvertisementBuilder::new(AdvLimit::Legacy)
+        .flags(FLAGS_GENERAL)
+        .services_16(&[EDDYSTONE_UUID], true)
+        .service_data_16(EDDYSTONE_UUID, frame)
+        .build()?;
+    Ok(Vec::from_slice(&adv.adv_data).unwrap_or_default())
+}
+
+// Scheme code followed by the URL with the expansions swapped for their codes
+// e.g. "https://example.com/" -> [0x03, "example", 0x00]
+// Only printable ASCII goes through as is, anything else would be read as an expansion code
+pub fn encode_url(url: &str) -> Result<Vec<u8, { 1 + URL_MAX_LEN }>, BeaconError> {
+    let (scheme, mut rest) = URL_SCHEMES.iter()
+        .enumerate()
+        .find_map(|(code, prefix)| url.strip_prefix(prefix).map(|rest| (code as u8, rest.as_bytes())))
+        .ok_or(BeaconError::UrlScheme)?;
+
+    let mut encoded: Vec<u8, { 1 + URL_MAX_LEN }> = Vec::new();
+    let _ = encoded.push(scheme);
+    while let [first, ..] = rest {
+        let expansion = URL_EXPANSIONS.iter().position(|expansion| rest.starts_with(expansion.as_bytes()));
+        let (byte, len) = match expansion {
+            Some(code) => (code as u8, URL_EXPANSIONS[code].len()),
+            None if (0x21..=0x7E).contains(first) => (*first, 1),
+            None => return Err(BeaconError::UrlCharacter),
+        };
+        encoded.push(byte).map_err(|_| BeaconError::UrlTooLong)?;
+        rest = &rest[len..];
+    }
+    Ok(encoded)
+}
+
+// Advertising events since boot, for the TLM advertising count
+// The SoftDevice radio notification pends EGU1_SWI1 as the radio turns on for each event. Nothing else uses the
+// radio while the beacon runs, so every radio event is an advertising event.
+static ADV_EVENTS: AtomicU32 = AtomicU32::new(0);
+
+#[interrupt]
+fn EGU1_SWI1() {
+    ADV_EVENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn count_adv_events() -> Result<(), RawError> {
+    // Same as the other HAL interrupts - keep it below the softdevice
+    interrupt::EGU1_SWI1.set_priority(Priority::P2);
+    interrupt::EGU1_SWI1.unpend();
+    unsafe { interrupt::EGU1_SWI1.enable() };
+
+    let ret = unsafe {
+        raw::sd_radio_notification_cfg_set(
+            raw::NRF_RADIO_NOTIFICATION_TYPES_NRF_RADIO_NOTIFICATION_TYPE_INT_ON_ACTIVE as u8,
+            raw::NRF_RADIO_NOTIFICATION_DISTANCES_NRF_RADIO_NOTIFICATION_DISTANCE_NONE as u8,
+        )
+    };
+    RawError::convert(ret)
+}
+
+// Cycle through the schedule until reset, e.g. UID, URL, UID, TLM to send telemetry every fourth frame
+// interval_ms is the advertising interval, the same for every frame
+#[embassy_executor::task]
+pub async fn beacon_rotation(schedule: &'static [BeaconSlot], interval_ms: u32) {
+    let mut broadcaster = Broadcaster::take();
+    let mut started = false;
+
+    if let Err(e) = count_adv_events() {
+        warn!("TLM advertising count unavailable: {:?}", e);
+    }
+
+    for slot in schedule.iter().cycle() {
+        let adv = match &slot.frame {
+            BeaconFrame::IBeacon(beacon) => ibeacon_adv(beacon),
+            BeaconFrame::EddystoneUid(uid) => eddystone_uid_adv(uid),
+            BeaconFrame::EddystoneUrl(url) => eddystone_url_adv(url),
+            BeaconFrame::EddystoneTlm => {
+                let adv_count = ADV_EVENTS.load(Ordering::Relaxed);
+                eddystone_tlm_adv(&Telemetry::from_snapshot(&state::snapshot(), adv_count, Instant::now()))
+            }
+        };
+
+        match adv {
+            Ok(adv) => {
+                let result = if started {
+                    broadcaster.update(&adv)
+                } else {
+                    broadcaster.start(&adv, interval_ms)
+                };
+                match result {
+                    Ok(()) => {
+                        started = true;
+                        d_info!("Beacon frame: {}", slot.frame);
+                    }
+                    Err(e) => warn!("Beacon advertising failed: {:?}", e),
+                }
+            }
+            Err(e) => warn!("Beacon frame skipped: {:?}", e),
+        }
+
+        Timer::after_millis(slot.dwell_ms).await;
+    }
+
+    warn!("Beacon schedule is empty");
+}
@@ -1,8 +1,21 @@
-use core::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
 use embassy_time::Timer;
-use nrf_softdevice::ble::gatt_server;
+use heapless::Vec;
+use nrf_softdevice::RawError;
+use nrf_softdevice::ble::Connection;
+use nrf_softdevice::ble::gatt_server::{self, NotifyValueError, SetValueError};
 
-use crate::{d_log::dlogger::DLogger, d_info};  // Logging
+use crate::embassy_hal::pac;
+use crate::system::bonding;
+use crate::system::config::{self, RuntimeConfig};
+use crate::system::diagnostics;
+use crate::system::history::{SharedHistory, RECORD_LEN};
+use crate::system::sensor_error::SensorUpdateError;
+use crate::system::update_policy::{Deadband, UpdatePolicy, UpdateTrigger};
+
+use crate::{d_log::dlogger::DLogger, d_info, warn};  // Logging
 
 /// GATT SERVICES (there are multiple)
 /// For examples and library documentation
@@ -15,10 +28,14 @@ pub struct BatteryService {
     pub battery_level: u8,
 }
 
+// Sensor service UUID as a number, for system::advertising
+pub const SENSOR_SERVICE_UUID: u128 = 0x9e7312e0_2354_11eb_9f10_fbc30a62cf38;
+
 // 128 bit UUIDs are custom and globally unique
 #[nrf_softdevice::gatt_service(uuid = "9e7312e0-2354-11eb-9f10-fbc30a62cf38")]
 pub struct SensorService {
 
+    // Whole degC, the 0.01 degC reading is on the ESS temperature characteristic (0x2A6E)
     #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf41", read, notify)]
     #[descriptor(uuid="2901", value="temperature_c")]  // Doesn't seem to do anything
     pub temperature_c: i32,
@@ -26,31 +43,344 @@ pub struct SensorService {
     #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf42", read, notify)]
     #[descriptor(uuid="2901", value="pressure_pa")]  // Doesn't seem to do anything
     pub pressure_pa: u32,
+
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf43", read, notify)]
+    #[descriptor(uuid="2901", value="iaq")]  // Doesn't seem to do anything
+    pub iaq: u16,
+
+    // Bluetooth SIG Illuminance format - uint24, 0.01 lux
+    #[characteristic(uuid = "2afb", read, notify)]
+    #[descriptor(uuid="2901", value="illuminance")]  // Doesn't seem to do anything
+    pub illuminance: [u8; 3],
+
+    // BME680 error state - 0 = ok, see SensorUpdateError::code
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf44", read, notify)]
+    #[descriptor(uuid="2901", value="sensor_status")]
+    pub sensor_status: u8,
+}
+
+// Bluetooth SIG Environmental Sensing Service
+// ES Measurement (0x290C): flags, sampling function, measurement period, update interval, application, uncertainty
+// Descriptor values are fixed when the GATT table is built while the sample period is runtime config,
+// so measurement period and update interval are 0 = not in use rather than a value that goes stale
+// ES Trigger Setting (0x290D): condition 0x03 = on value change, which is what the deadband policies do
+const ES_MEASUREMENT: [u8; 11] = [0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00];
+const ES_TRIGGER_SETTING: [u8; 1] = [0x03];
+
+#[nrf_softdevice::gatt_service(uuid = "181a")]
+pub struct EnvironmentalSensingService {
+
+    // sint16, 0.01 degC
+    #[characteristic(uuid = "2a6e", read, notify)]
+    #[descriptor(uuid="290c", value=&ES_MEASUREMENT)]
+    #[descriptor(uuid="290d", value=&ES_TRIGGER_SETTING)]
+    pub temperature: i16,
+
+    // uint32, 0.1 Pa
+    #[characteristic(uuid = "2a6d", read, notify)]
+    #[descriptor(uuid="290c", value=&ES_MEASUREMENT)]
+    #[descriptor(uuid="290d", value=&ES_TRIGGER_SETTING)]
+    pub pressure: u32,
+
+    // uint16, 0.01 %RH
+    #[characteristic(uuid = "2a6f", read, notify)]
+    #[descriptor(uuid="290c", value=&ES_MEASUREMENT)]
+    #[descriptor(uuid="290d", value=&ES_TRIGGER_SETTING)]
+    pub humidity: u16,
+}
+
+// Runtime configuration, see system::config for the accepted ranges
+// Rejected writes are logged and the characteristic is put back to the active value
+// Reading or writing needs an encrypted link, writes are only accepted from a bonded central (system::bonding)
+#[nrf_softdevice::gatt_service(uuid = "9e7312e0-2354-11eb-9f10-fbc30a62cf50")]
+pub struct ConfigService {
+
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf51", read, write, security = "JustWorks")]
+    #[descriptor(uuid="2901", value="sample_period_ms")]
+    pub sample_period_ms: u32,
+
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf52", read, write, security = "JustWorks")]
+    #[descriptor(uuid="2901", value="notify_period_ms")]
+    pub notify_period_ms: u32,
+
+    // osrs_t, osrs_p, osrs_h - 0 = skip, 1..5 = x1..x16
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf53", read, write, security = "JustWorks")]
+    #[descriptor(uuid="2901", value="oversampling")]
+    pub oversampling: [u8; 3],
+}
+
+// Sensor history download, modelled on the Record Access Control Point (0x2A52)
+// Requests written to control_point, records arrive as notifications on records - see serve_history
+#[nrf_softdevice::gatt_service(uuid = "9e7312e0-2354-11eb-9f10-fbc30a62cf60")]
+pub struct HistoryService {
+
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf61", write, notify)]
+    #[descriptor(uuid="2901", value="record_access_control_point")]
+    pub control_point: Vec<u8, 8>,
+
+    // seq u32, boot u16, uptime_s u32, temperature i16 (0.01 degC), pressure u32 (Pa), humidity u16 (0.01 %RH), iaq u16
+    // uptime_s restarts every boot - order by seq, compare uptime_s only within one boot
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf62", notify)]
+    #[descriptor(uuid="2901", value="records")]
+    pub records: [u8; RECORD_LEN],
+}
+
+// Node health counters since boot, refreshed by update_diagnostics() while connected
+#[nrf_softdevice::gatt_service(uuid = "9e7312e0-2354-11eb-9f10-fbc30a62cf70")]
+pub struct DiagnosticsService {
+
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf71", read)]
+    #[descriptor(uuid="2901", value="read_count")]
+    pub read_count: u32,
+
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf72", read)]
+    #[descriptor(uuid="2901", value="i2c_errors")]
+    pub i2c_errors: u32,
+
+    // Latest SensorUpdateError::code, kept after the sensor recovers
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf73", read)]
+    #[descriptor(uuid="2901", value="last_error")]
+    pub last_error: u8,
+
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf74", read)]
+    #[descriptor(uuid="2901", value="reinit_count")]
+    pub reinit_count: u32,
+
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf75", read)]
+    #[descriptor(uuid="2901", value="uptime_s")]
+    pub uptime_s: u32,
+
+    // nRF52840 POWER.RESETREAS bits, 0 = power-on reset
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf76", read)]
+    #[descriptor(uuid="2901", value="reset_reason")]
+    pub reset_reason: u32,
+
+    // Bytes of stack never used since boot
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf77", read)]
+    #[descriptor(uuid="2901", value="free_stack")]
+    pub free_stack: u32,
+
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf78", read)]
+    #[descriptor(uuid="2901", value="connections")]
+    pub connections: u32,
+
+    #[characteristic(uuid = "9e7312e0-2354-11eb-9f10-fbc30a63cf79", read)]
+    #[descriptor(uuid="2901", value="notify_dropped")]
+    pub notify_dropped: u32,
 }
 
+// Device Information Service - values are written once by init_device_info()
+#[nrf_softdevice::gatt_service(uuid = "180a")]
+pub struct DeviceInfoService {
+    #[characteristic(uuid = "2a29", read)]
+    pub manufacturer_name: Vec<u8, 32>,
+
+    #[characteristic(uuid = "2a24", read)]
+    pub model_number: Vec<u8, 32>,
+
+    #[characteristic(uuid = "2a25", read)]
+    pub serial_number: Vec<u8, 32>,
+
+    #[characteristic(uuid = "2a26", read)]
+    pub firmware_revision: Vec<u8, 32>,
+
+    #[characteristic(uuid = "2a27", read)]
+    pub hardware_revision: Vec<u8, 32>,
+}
+
+const MANUFACTURER_NAME: &str = "dniamir";
+const MODEL_NUMBER: &str = "nRF52840 sensor node";
+
+// Baked in by build.rs
+const FIRMWARE_REVISION: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("GIT_HASH"));
+const HARDWARE_REVISION: &str = env!("HW_REVISION");
+
+// CCCD state for the current connection, written from handle_ble_event and restore_subscriptions
+pub struct Subscriptions {
+    pub battery_level: AtomicBool,
+    pub temperature_c: AtomicBool,
+    pub pressure_pa: AtomicBool,
+    pub iaq: AtomicBool,
+    pub illuminance: AtomicBool,
+    pub sensor_status: AtomicBool,
+    pub ess_temperature: AtomicBool,
+    pub ess_pressure: AtomicBool,
+    pub ess_humidity: AtomicBool,
+    pub history_control_point: AtomicBool,
+    pub history_records: AtomicBool,
+}
+
+impl Subscriptions {
+    const fn new() -> Self {
+        Subscriptions {
+            battery_level: AtomicBool::new(false),
+            temperature_c: AtomicBool::new(false),
+            pressure_pa: AtomicBool::new(false),
+            iaq: AtomicBool::new(false),
+            illuminance: AtomicBool::new(false),
+            sensor_status: AtomicBool::new(false),
+            ess_temperature: AtomicBool::new(false),
+            ess_pressure: AtomicBool::new(false),
+            ess_humidity: AtomicBool::new(false),
+            history_control_point: AtomicBool::new(false),
+            history_records: AtomicBool::new(false),
+        }
+    }
+
+    // Flag for the characteristic with this CCCD handle
+    fn for_cccd(&self, server: &BLEServer, handle: u16) -> Option<&AtomicBool> {
+        let flags = [
+            (server.batt_service.battery_level_cccd_handle, &self.battery_level),
+            (server.sensor_service.temperature_c_cccd_handle, &self.temperature_c),
+            (server.sensor_service.pressure_pa_cccd_handle, &self.pressure_pa),
+            (server.sensor_service.iaq_cccd_handle, &self.iaq),
+            (server.sensor_service.illuminance_cccd_handle, &self.illuminance),
+            (server.sensor_service.sensor_status_cccd_handle, &self.sensor_status),
+            (server.ess_service.temperature_cccd_handle, &self.ess_temperature),
+            (server.ess_service.pressure_cccd_handle, &self.ess_pressure),
+            (server.ess_service.humidity_cccd_handle, &self.ess_humidity),
+            (server.history_service.control_point_cccd_handle, &self.history_control_point),
+            (server.history_service.records_cccd_handle, &self.history_records),
+        ];
+        flags.into_iter().find(|(cccd, _)| *cccd == handle).map(|(_, flag)| flag)
+    }
+
+    pub fn clear(&self) {
+        for flag in [
+            &self.battery_level, &self.temperature_c, &self.pressure_pa, &self.iaq,
+            &self.illuminance, &self.sensor_status, &self.ess_temperature, &self.ess_pressure, &self.ess_humidity,
+            &self.history_control_point, &self.history_records,
+        ] {
+            flag.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+pub static SUBSCRIPTIONS: Subscriptions = Subscriptions::new();
+
+// Record access opcodes, operators and response codes (subset of the RACP values)
+const RACP_REPORT_RECORDS: u8 = 0x01;
+const RACP_ABORT: u8 = 0x03;
+const RACP_REPORT_COUNT: u8 = 0x04;
+const RACP_COUNT_RESPONSE: u8 = 0x05;
+const RACP_RESPONSE_CODE: u8 = 0x06;
+
+const RACP_OPERATOR_NULL: u8 = 0x00;
+const RACP_OPERATOR_ALL: u8 = 0x01;
+const RACP_OPERATOR_GREATER_OR_EQUAL: u8 = 0x03;   // Operand: sequence number, u32 LE
+
+const RACP_SUCCESS: u8 = 0x01;
+const RACP_OPCODE_NOT_SUPPORTED: u8 = 0x02;
+const RACP_INVALID_OPERATOR: u8 = 0x03;
+const RACP_INVALID_OPERAND: u8 = 0x05;
+const RACP_NO_RECORDS: u8 = 0x06;
+const RACP_NOT_COMPLETED: u8 = 0x08;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum HistoryRequest {
+    Report { since: u32 },
+    Count { since: u32 },
+    Abort,
+    Invalid { opcode: u8, code: u8 },
+}
+
+// Written from handle_ble_event, served by serve_history
+pub static HISTORY_REQUEST: Signal<ThreadModeRawMutex, HistoryRequest> = Signal::new();
+
 // GATT SERVER (there can only be one)
 
 #[nrf_softdevice::gatt_server]
 pub struct BLEServer {
     pub batt_service: BatteryService,
     pub sensor_service: SensorService,
+    pub ess_service: EnvironmentalSensingService,
+    pub dis_service: DeviceInfoService,
+    pub config_service: ConfigService,
+    pub history_service: HistoryService,
+    pub diag_service: DiagnosticsService,
 }
 
 // Create the gatt_future to run later
 // gatt_server::run is an async function that returns a future
-pub fn my_gatt_server<'a>(conn: &'a nrf_softdevice::ble::Connection, server: &'a BLEServer) -> impl core::future::Future<Output = ()> + 'a {
+pub fn my_gatt_server<'a>(conn: &'a Connection, server: &'a BLEServer) -> impl core::future::Future<Output = ()> + 'a {
     async move {
-        let _ = gatt_server::run(conn, server, handle_ble_event).await;
+        // Every connection starts unsubscribed, with no history request pending
+        // Bonded centrals get their subscriptions back through restore_subscriptions
+        SUBSCRIPTIONS.clear();
+        HISTORY_REQUEST.reset();
+        diagnostics::CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        let _ = gatt_server::run(conn, server, |e| handle_ble_event(server, e)).await;
     }
 }
 
+// Fill in the Device Information Service, call once after BLEServer::new
+pub fn init_device_info(server: &BLEServer) {
+    let serial = device_serial();
+
+    let _ = server.dis_service.manufacturer_name_set(&str_value(MANUFACTURER_NAME));
+    let _ = server.dis_service.model_number_set(&str_value(MODEL_NUMBER));
+    let _ = server.dis_service.serial_number_set(&serial);
+    let _ = server.dis_service.firmware_revision_set(&str_value(FIRMWARE_REVISION));
+    let _ = server.dis_service.hardware_revision_set(&str_value(HARDWARE_REVISION));
+
+    d_info!("Device info - serial: {=[u8]:a}, firmware: {}, hardware: {}", serial.as_slice(), FIRMWARE_REVISION, HARDWARE_REVISION);
+}
+
+// 64 bit FICR device ID as 16 upper-case hex characters
+pub fn device_serial() -> Vec<u8, 32> {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+    let device_id = ((pac::FICR.deviceid(1).read() as u64) << 32) | pac::FICR.deviceid(0).read() as u64;
+
+    let mut serial = Vec::new();
+    for shift in (0..16).rev() {
+        let _ = serial.push(HEX[((device_id >> (shift * 4)) & 0xF) as usize]);
+    }
+    serial
+}
+
+// Load the active runtime config into the config service, call once after BLEServer::new
+pub fn init_config(server: &BLEServer) {
+    set_config_values(server, &config::current());
+}
+
+fn set_config_values(server: &BLEServer, runtime: &RuntimeConfig) {
+    let _ = server.config_service.sample_period_ms_set(&runtime.sample_ms);
+    let _ = server.config_service.notify_period_ms_set(&runtime.notify_ms);
+    let _ = server.config_service.oversampling_set(&runtime.oversampling);
+}
+
+// Publish a written config, or restore the characteristics if it doesn't validate
+// or the central isn't bonded
+fn write_config(server: &BLEServer, runtime: RuntimeConfig) {
+    if !bonding::link_bonded() {
+        warn!("Runtime config rejected: link not bonded");
+        set_config_values(server, &config::current());
+        return;
+    }
+
+    match config::set(runtime) {
+        Ok(()) => d_info!("Runtime config updated: {}", runtime),
+        Err(e) => {
+            warn!("Runtime config rejected: {:?}", e);
+            set_config_values(server, &config::current());
+        }
+    }
+}
+
+fn str_value(val: &str) -> Vec<u8, 32> {
+    let bytes = val.as_bytes();
+    Vec::from_slice(&bytes[..bytes.len().min(32)]).unwrap_or_default()
+}
+
 // Define gatt server services
-fn handle_ble_event(e: BLEServerEvent) {
+fn handle_ble_event(server: &BLEServer, e: BLEServerEvent) {
     match e {
         // Battery service
         BLEServerEvent::BattService(e) => match e {
             BatteryServiceEvent::BatteryLevelCccdWrite { notifications } => {
                 d_info!("battery notifications: {}", notifications);
+                SUBSCRIPTIONS.battery_level.store(notifications, Ordering::Relaxed);
             }
         },
 
@@ -58,34 +388,464 @@ fn handle_ble_event(e: BLEServerEvent) {
         BLEServerEvent::SensorService(e) => match e {
             SensorServiceEvent::TemperatureCCccdWrite { notifications } => {
                 d_info!("temperature_c notifications: {}", notifications);
+                SUBSCRIPTIONS.temperature_c.store(notifications, Ordering::Relaxed);
             }
             SensorServiceEvent::PressurePaCccdWrite { notifications } => {
                 d_info!("pressure_c notifications: {}", notifications);
+                SUBSCRIPTIONS.pressure_pa.store(notifications, Ordering::Relaxed);
+            }
+            SensorServiceEvent::IaqCccdWrite { notifications } => {
+                d_info!("iaq notifications: {}", notifications);
+                SUBSCRIPTIONS.iaq.store(notifications, Ordering::Relaxed);
+            }
+            SensorServiceEvent::IlluminanceCccdWrite { notifications } => {
+                d_info!("illuminance notifications: {}", notifications);
+                SUBSCRIPTIONS.illuminance.store(notifications, Ordering::Relaxed);
+            }
+            SensorServiceEvent::SensorStatusCccdWrite { notifications } => {
+                d_info!("sensor_status notifications: {}", notifications);
+                SUBSCRIPTIONS.sensor_status.store(notifications, Ordering::Relaxed);
+            }
+        },
+
+        // Environmental sensing service
+        BLEServerEvent::EssService(e) => match e {
+            EnvironmentalSensingServiceEvent::TemperatureCccdWrite { notifications } => {
+                d_info!("ess temperature notifications: {}", notifications);
+                SUBSCRIPTIONS.ess_temperature.store(notifications, Ordering::Relaxed);
+            }
+            EnvironmentalSensingServiceEvent::PressureCccdWrite { notifications } => {
+                d_info!("ess pressure notifications: {}", notifications);
+                SUBSCRIPTIONS.ess_pressure.store(notifications, Ordering::Relaxed);
+            }
+            EnvironmentalSensingServiceEvent::HumidityCccdWrite { notifications } => {
+                d_info!("ess humidity notifications: {}", notifications);
+                SUBSCRIPTIONS.ess_humidity.store(notifications, Ordering::Relaxed);
+            }
+        },
+
+        // Config service
+        BLEServerEvent::ConfigService(e) => {
+            let mut runtime = config::current();
+            match e {
+                ConfigServiceEvent::SamplePeriodMsWrite(val) => runtime.sample_ms = val,
+                ConfigServiceEvent::NotifyPeriodMsWrite(val) => runtime.notify_ms = val,
+                ConfigServiceEvent::OversamplingWrite(val) => runtime.oversampling = val,
+            }
+            write_config(server, runtime);
+        }
+
+        // History service
+        BLEServerEvent::HistoryService(e) => match e {
+            HistoryServiceEvent::ControlPointWrite(val) => {
+                let request = parse_history_request(&val);
+                d_info!("history request: {}", request);
+                HISTORY_REQUEST.signal(request);
+            }
+            HistoryServiceEvent::ControlPointCccdWrite { notifications } => {
+                d_info!("history control point notifications: {}", notifications);
+                SUBSCRIPTIONS.history_control_point.store(notifications, Ordering::Relaxed);
+            }
+            HistoryServiceEvent::RecordsCccdWrite { notifications } => {
+                d_info!("history records notifications: {}", notifications);
+                SUBSCRIPTIONS.history_records.store(notifications, Ordering::Relaxed);
             }
         },
     }
 }
 
-pub async fn update_temperature(server: &BLEServer, atomic: &AtomicI32, update_ms: u64) {
-    loop {
-        Timer::after_millis(update_ms).await;
+// Notify when the client subscribed, otherwise only update the GATT table
+// Running out of TX buffers drops this sample, any other notify error is returned so the caller
+// can drop the connection - see ble_bme_char
+fn push_value(
+    name: &str,
+    subscribed: &AtomicBool,
+    notify: impl FnOnce() -> Result<(), NotifyValueError>,
+    set: impl FnOnce() -> Result<(), SetValueError>,
+) -> Result<(), NotifyValueError> {
+    if !subscribed.load(Ordering::Relaxed) {
+        log_set(name, set());
+        return Ok(());
+    }
+
+    match notify() {
+        Ok(()) => Ok(()),
+        Err(NotifyValueError::Raw(RawError::Resources)) => {
+            warn!("{} notify dropped: out of TX buffers", name);
+            diagnostics::NOTIFY_DROPPED.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+        Err(e) => {
+            warn!("{} notify failed: {:?}", name, e);
+            Err(e)
+        }
+    }
+}
 
-        let char_val = atomic.load(Ordering::Relaxed);
+// Snapshot holds 0.01 degC, the characteristic whole degC - deadband in degC
+pub async fn update_temperature(server: &BLEServer, conn: &Connection, policy: UpdatePolicy) -> Result<(), NotifyValueError> {
+    let service = &server.sensor_service;
+    let mut trigger = UpdateTrigger::new(policy);
+    let mut deadband = Deadband::new(policy);
+    loop {
+        let Some(char_val) = trigger.wait().await.temperature().map(|temp| temp / 100) else {
+            continue;
+        };
+        if !deadband.check(char_val as i64) {
+            continue;
+        }
 
-        let _ = server.sensor_service.temperature_c_set(&char_val);
+        push_value(
+            "temperature_c",
+            &SUBSCRIPTIONS.temperature_c,
+            || service.temperature_c_notify(conn, &char_val),
+            || service.temperature_c_set(&char_val),
+        )?;
         d_info!("Updated temperature_c characteristic: {}", char_val);
         DLogger::d_sep();
     }
 }
 
-pub async fn update_pressure(server: &BLEServer, atomic: &AtomicU32, update_ms: u64) {
+pub async fn update_pressure(server: &BLEServer, conn: &Connection, policy: UpdatePolicy) -> Result<(), NotifyValueError> {
+    let service = &server.sensor_service;
+    let mut trigger = UpdateTrigger::new(policy);
+    let mut deadband = Deadband::new(policy);
     loop {
+        let Some(char_val) = trigger.wait().await.pressure() else {
+            continue;
+        };
+        if !deadband.check(char_val as i64) {
+            continue;
+        }
+
+        push_value(
+            "pressure_pa",
+            &SUBSCRIPTIONS.pressure_pa,
+            || service.pressure_pa_notify(conn, &char_val),
+            || service.pressure_pa_set(&char_val),
+        )?;
+        d_info!("Updated pressure_pa characteristic: {}", char_val);
+        DLogger::d_sep();
+    }
+}
+
+pub async fn update_iaq(server: &BLEServer, conn: &Connection, policy: UpdatePolicy) -> Result<(), NotifyValueError> {
+    let service = &server.sensor_service;
+    let mut trigger = UpdateTrigger::new(policy);
+    let mut deadband = Deadband::new(policy);
+    loop {
+        let Some(char_val) = trigger.wait().await.iaq() else {
+            continue;
+        };
+        if !deadband.check(char_val as i64) {
+            continue;
+        }
+
+        push_value(
+            "iaq",
+            &SUBSCRIPTIONS.iaq,
+            || service.iaq_notify(conn, &char_val),
+            || service.iaq_set(&char_val),
+        )?;
+        d_info!("Updated iaq characteristic: {}", char_val);
+        DLogger::d_sep();
+    }
+}
+
+// Snapshot holds 0.01 lux, the characteristic is a little-endian uint24 in the same unit
+pub async fn update_illuminance(server: &BLEServer, conn: &Connection, policy: UpdatePolicy) -> Result<(), NotifyValueError> {
+    let service = &server.sensor_service;
+    let mut trigger = UpdateTrigger::new(policy);
+    let mut deadband = Deadband::new(policy);
+    loop {
+        let Some(lux_val) = trigger.wait().await.lux() else {
+            continue;
+        };
+        let lux_val = lux_val.min(0xFF_FFFF);
+        if !deadband.check(lux_val as i64) {
+            continue;
+        }
+        let [b0, b1, b2, _] = lux_val.to_le_bytes();
+        let char_val = [b0, b1, b2];
+
+        push_value(
+            "illuminance",
+            &SUBSCRIPTIONS.illuminance,
+            || service.illuminance_notify(conn, &char_val),
+            || service.illuminance_set(&char_val),
+        )?;
+        d_info!("Updated illuminance characteristic: {}", lux_val);
+        DLogger::d_sep();
+    }
+}
+
+// Pushed whenever the BME680 error state changes
+pub async fn update_sensor_status(server: &BLEServer, conn: &Connection) -> Result<(), NotifyValueError> {
+    let service = &server.sensor_service;
+    let policy = UpdatePolicy::OnChange { deadband: 1 };
+    let mut trigger = UpdateTrigger::new(policy);
+    let mut deadband = Deadband::new(policy);
+    loop {
+        let char_val = SensorUpdateError::code(trigger.wait().await.bme_error);
+        if !deadband.check(char_val as i64) {
+            continue;
+        }
+
+        push_value(
+            "sensor_status",
+            &SUBSCRIPTIONS.sensor_status,
+            || service.sensor_status_notify(conn, &char_val),
+            || service.sensor_status_set(&char_val),
+        )?;
+        d_info!("Updated sensor_status characteristic: {}", char_val);
+        DLogger::d_sep();
+    }
+}
+
+// A bonded central gets its CCCDs back from system::bonding without writing them again,
+// so no CccdWrite events arrive - set the flags from the restored values instead
+pub async fn restore_subscriptions(server: &BLEServer) {
+    loop {
+        let sys_attrs = bonding::RESTORED_SYS_ATTRS.wait().await;
+        for (handle, value) in bonding::cccd_values(&sys_attrs) {
+            if let Some(flag) = SUBSCRIPTIONS.for_cccd(server, handle) {
+                flag.store(value & bonding::CCCD_NOTIFY != 0, Ordering::Relaxed);
+            }
+        }
+        d_info!("Subscriptions restored for bonded central");
+    }
+}
+
+// Copy the diagnostics counters into the GATT table every update_ms
+// Read-only, so the values a client reads are at most update_ms old
+pub async fn update_diagnostics(server: &BLEServer, update_ms: u64) {
+    let service = &server.diag_service;
+    log_set("reset_reason", service.reset_reason_set(&diagnostics::reset_reason()));
+    loop {
+        log_set("read_count", service.read_count_set(&diagnostics::SENSOR_READS.load(Ordering::Relaxed)));
+        log_set("i2c_errors", service.i2c_errors_set(&diagnostics::I2C_ERRORS.load(Ordering::Relaxed)));
+        log_set("last_error", service.last_error_set(&diagnostics::LAST_ERROR.load(Ordering::Relaxed)));
+        log_set("reinit_count", service.reinit_count_set(&diagnostics::SENSOR_REINITS.load(Ordering::Relaxed)));
+        log_set("uptime_s", service.uptime_s_set(&diagnostics::uptime_s()));
+        log_set("free_stack", service.free_stack_set(&diagnostics::free_stack()));
+        log_set("connections", service.connections_set(&diagnostics::CONNECTIONS.load(Ordering::Relaxed)));
+        log_set("notify_dropped", service.notify_dropped_set(&diagnostics::NOTIFY_DROPPED.load(Ordering::Relaxed)));
+
         Timer::after_millis(update_ms).await;
+    }
+}
 
-        let char_val = atomic.load(Ordering::Relaxed);
+// Failed GATT table writes are only logged, the next update writes the value again
+fn log_set(name: &str, result: Result<(), SetValueError>) {
+    if let Err(e) = result {
+        warn!("{} set failed: {:?}", name, e);
+    }
+}
 
-        let _ = server.sensor_service.pressure_pa_set(&char_val);
-        d_info!("Updated pressure_pa characteristic: {}", char_val);
+// ESS values in SIG units from the system::state snapshot
+// Deadbands are in the characteristic unit - temp: 0.01 degC, pressure: 0.1 Pa, humidity: 0.01 %RH
+pub async fn update_ess_temperature(server: &BLEServer, conn: &Connection, policy: UpdatePolicy) -> Result<(), NotifyValueError> {
+    let service = &server.ess_service;
+    let mut trigger = UpdateTrigger::new(policy);
+    let mut deadband = Deadband::new(policy);
+    loop {
+        let Some(temp_val) = trigger.wait().await.temperature() else {
+            continue;
+        };
+        let char_val = temp_val.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        if !deadband.check(char_val as i64) {
+            continue;
+        }
+
+        push_value(
+            "ess temperature",
+            &SUBSCRIPTIONS.ess_temperature,
+            || service.temperature_notify(conn, &char_val),
+            || service.temperature_set(&char_val),
+        )?;
+        d_info!("Updated ess temperature characteristic: {}", char_val);
+        DLogger::d_sep();
+    }
+}
+
+// Snapshot holds Pa
+pub async fn update_ess_pressure(server: &BLEServer, conn: &Connection, policy: UpdatePolicy) -> Result<(), NotifyValueError> {
+    let service = &server.ess_service;
+    let mut trigger = UpdateTrigger::new(policy);
+    let mut deadband = Deadband::new(policy);
+    loop {
+        let Some(pressure_val) = trigger.wait().await.pressure() else {
+            continue;
+        };
+        let char_val = pressure_val.saturating_mul(10);
+        if !deadband.check(char_val as i64) {
+            continue;
+        }
+
+        push_value(
+            "ess pressure",
+            &SUBSCRIPTIONS.ess_pressure,
+            || service.pressure_notify(conn, &char_val),
+            || service.pressure_set(&char_val),
+        )?;
+        d_info!("Updated ess pressure characteristic: {}", char_val);
+        DLogger::d_sep();
+    }
+}
+
+// Snapshot holds 0.001 %RH
+pub async fn update_ess_humidity(server: &BLEServer, conn: &Connection, policy: UpdatePolicy) -> Result<(), NotifyValueError> {
+    let service = &server.ess_service;
+    let mut trigger = UpdateTrigger::new(policy);
+    let mut deadband = Deadband::new(policy);
+    loop {
+        let Some(humidity_val) = trigger.wait().await.humidity() else {
+            continue;
+        };
+        let char_val = (humidity_val / 10).min(10_000) as u16;
+        if !deadband.check(char_val as i64) {
+            continue;
+        }
+
+        push_value(
+            "ess humidity",
+            &SUBSCRIPTIONS.ess_humidity,
+            || service.humidity_notify(conn, &char_val),
+            || service.humidity_set(&char_val),
+        )?;
+        d_info!("Updated ess humidity characteristic: {}", char_val);
         DLogger::d_sep();
     }
-}
\ No newline at end of file
+}
+
+pub async fn update_battery(server: &BLEServer, conn: &Connection, policy: UpdatePolicy) -> Result<(), NotifyValueError> {
+    let service = &server.batt_service;
+    let mut trigger = UpdateTrigger::new(policy);
+    let mut deadband = Deadband::new(policy);
+    loop {
+        let Some(level) = trigger.wait().await.battery_level() else {
+            continue;
+        };
+        let char_val = level.min(100);
+        if !deadband.check(char_val as i64) {
+            continue;
+        }
+
+        push_value(
+            "battery_level",
+            &SUBSCRIPTIONS.battery_level,
+            || service.battery_level_notify(conn, &char_val),
+            || service.battery_level_set(&char_val),
+        )?;
+        d_info!("Updated battery_level characteristic: {}", char_val);
+        DLogger::d_sep();
+    }
+}
+
+// [opcode, operator, operand...]
+fn parse_history_request(bytes: &[u8]) -> HistoryRequest {
+    let (opcode, operator, operand) = match bytes {
+        [opcode, operator, operand @ ..] => (*opcode, *operator, operand),
+        [opcode] => return HistoryRequest::Invalid { opcode: *opcode, code: RACP_INVALID_OPERATOR },
+        [] => return HistoryRequest::Invalid { opcode: 0, code: RACP_OPCODE_NOT_SUPPORTED },
+    };
+
+    let since = match (opcode, operator, operand) {
+        (RACP_ABORT, RACP_OPERATOR_NULL, []) => return HistoryRequest::Abort,
+        (RACP_ABORT, _, _) => return HistoryRequest::Invalid { opcode, code: RACP_INVALID_OPERATOR },
+        (RACP_REPORT_RECORDS | RACP_REPORT_COUNT, RACP_OPERATOR_ALL, []) => 0,
+        (RACP_REPORT_RECORDS | RACP_REPORT_COUNT, RACP_OPERATOR_GREATER_OR_EQUAL, &[b0, b1, b2, b3]) => u32::from_le_bytes([b0, b1, b2, b3]),
+        (RACP_REPORT_RECORDS | RACP_REPORT_COUNT, RACP_OPERATOR_ALL | RACP_OPERATOR_GREATER_OR_EQUAL, _) => {
+            return HistoryRequest::Invalid { opcode, code: RACP_INVALID_OPERAND };
+        }
+        (RACP_REPORT_RECORDS | RACP_REPORT_COUNT, _, _) => return HistoryRequest::Invalid { opcode, code: RACP_INVALID_OPERATOR },
+        _ => return HistoryRequest::Invalid { opcode, code: RACP_OPCODE_NOT_SUPPORTED },
+    };
+
+    match opcode {
+        RACP_REPORT_RECORDS => HistoryRequest::Report { since },
+        _ => HistoryRequest::Count { since },
+    }
+}
+
+// Unlike push_value a history notification can't be dropped - wait for a TX buffer instead
+async fn notify_retry(mut notify: impl FnMut() -> Result<(), NotifyValueError>) -> Result<(), NotifyValueError> {
+    loop {
+        match notify() {
+            Err(NotifyValueError::Raw(RawError::Resources)) => Timer::after_millis(10).await,
+            result => return result,
+        }
+    }
+}
+
+async fn history_respond(server: &BLEServer, conn: &Connection, response: &[u8]) -> Result<(), NotifyValueError> {
+    if !SUBSCRIPTIONS.history_control_point.load(Ordering::Relaxed) {
+        warn!("history response dropped: control point not subscribed");
+        return Ok(());
+    }
+    let val: Vec<u8, 8> = Vec::from_slice(response).unwrap_or_default();
+    notify_retry(|| server.history_service.control_point_notify(conn, &val)).await
+}
+
+// Stream records since a sequence number, stopping early when another request comes in
+async fn history_stream(server: &BLEServer, conn: &Connection, history: &SharedHistory, since: u32) -> Result<u8, NotifyValueError> {
+    if !SUBSCRIPTIONS.history_records.load(Ordering::Relaxed) {
+        warn!("history records not subscribed");
+        return Ok(RACP_NOT_COMPLETED);
+    }
+
+    let (oldest, next) = history.lock().await.range();
+    let start = since.max(oldest);
+    if start >= next {
+        return Ok(RACP_NO_RECORDS);
+    }
+
+    for seq in start..next {
+        if HISTORY_REQUEST.signaled() {
+            d_info!("history stream interrupted at {}", seq);
+            return Ok(RACP_NOT_COMPLETED);
+        }
+
+        let record = match history.lock().await.read(seq).await {
+            Ok(Some(record)) => record,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("history read failed: {:?}", e);
+                return Ok(RACP_NOT_COMPLETED);
+            }
+        };
+        let char_val = record.to_bytes();
+        notify_retry(|| server.history_service.records_notify(conn, &char_val)).await?;
+    }
+
+    d_info!("history streamed records {} to {}", start, next);
+    Ok(RACP_SUCCESS)
+}
+
+// Answer history service requests for the lifetime of a connection
+// history is None when the log couldn't be mounted, requests are then answered as an empty log
+pub async fn serve_history(server: &BLEServer, conn: &Connection, history: Option<&SharedHistory>) -> Result<(), NotifyValueError> {
+    loop {
+        let (opcode, code) = match HISTORY_REQUEST.wait().await {
+            HistoryRequest::Report { since } => match history {
+                Some(history) => (RACP_REPORT_RECORDS, history_stream(server, conn, history, since).await?),
+                None => (RACP_REPORT_RECORDS, RACP_NO_RECORDS),
+            },
+            HistoryRequest::Count { since } => {
+                let count = match history {
+                    Some(history) => {
+                        let (oldest, next) = history.lock().await.range();
+                        next.saturating_sub(since.max(oldest))
+                    }
+                    None => 0,
+                };
+                let [b0, b1, b2, b3] = count.to_le_bytes();
+                history_respond(server, conn, &[RACP_COUNT_RESPONSE, RACP_OPERATOR_NULL, b0, b1, b2, b3]).await?;
+                continue;
+            }
+            HistoryRequest::Abort => (RACP_ABORT, RACP_SUCCESS),
+            HistoryRequest::Invalid { opcode, code } => (opcode, code),
+        };
+        history_respond(server, conn, &[RACP_RESPONSE_CODE, RACP_OPERATOR_NULL, opcode, code]).await?;
+    }
+}
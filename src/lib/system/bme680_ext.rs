@@ -0,0 +1,608 @@
+/// BME680 readouts that the base d_peripherals driver does not cover yet
+/// Compensation formulas follow the integer versions in the Bosch BME680 datasheet / BME68x API
+use embassy_time::Timer;
+
+use crate::d_peripherals::sensors::bme680::BME680;
+
+use crate::d_info;  // Logging
+
+// Calibration register blocks
+pub const CHIP_ID_REG: u8 = 0xD0;
+pub const CHIP_ID: u8 = 0x61;
+
+const CALIB_BLOCK_0: u8 = 0x00;     // res_heat_val .. range_sw_err
+const CALIB_BLOCK_1: u8 = 0x8A;     // par_t2 .. par_p10
+const CALIB_BLOCK_2: u8 = 0xE1;     // par_h2 .. par_g3
+
+// Data registers
+const MEAS_STATUS_0: u8 = 0x1D;     // Start of the 0x1D..0x2B data burst
+const DATA_BURST_LEN: usize = 15;
+
+// Gas registers and bits, addressed directly until chip_map (d_peripherals) gains res_heat_x, gas_wait_x,
+// run_gas, nb_conv, heat_off, gas_valid_r and heat_stab_r - see BACKLOG.md
+const RES_HEAT_0: u8 = 0x5A;        // res_heat_0 .. res_heat_9
+const GAS_WAIT_0: u8 = 0x64;        // gas_wait_0 .. gas_wait_9
+const CTRL_GAS_0: u8 = 0x70;
+const CTRL_GAS_1: u8 = 0x71;
+
+// meas_status_0 / gas_r_lsb bits
+const NEW_DATA_0: u8 = 1 << 7;
+const GAS_MEASURING: u8 = 1 << 6;
+const MEASURING: u8 = 1 << 5;
+const GAS_VALID_R: u8 = 1 << 5;
+const HEAT_STAB_R: u8 = 1 << 4;
+
+// ctrl_gas_0 / ctrl_gas_1 fields
+const HEAT_OFF: u8 = 1 << 3;
+const RUN_GAS: u8 = 1 << 4;
+const NB_CONV_MASK: u8 = 0x0F;
+
+const MEAS_POLL_MS: u64 = 5;
+const MEAS_POLL_TRIES: u32 = 20;
+
+// Heater set-points, one res_heat_x / gas_wait_x register pair per profile
+pub const HEATER_PROFILES: usize = 10;
+
+// Gas range lookup tables from the BME680 datasheet
+const GAS_LOOKUP_1: [u32; 16] = [
+    2147483647, 2147483647, 2147483647, 2147483647, 2147483647, 2126008810, 2147483647, 2130303777,
+    2147483647, 2147483647, 2143188679, 2136746228, 2147483647, 2126008810, 2147483647, 2147483647,
+];
+const GAS_LOOKUP_2: [u32; 16] = [
+    4096000000, 2048000000, 1024000000, 512000000, 255744255, 127110228, 64000000, 32258064,
+    16016016, 8000000, 4000000, 2000000, 1000000, 500000, 250000, 125000,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum BME680ExtError {
+    Bus,
+    InvalidProfile,
+    Timeout,
+    InvalidConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Oversampling {
+    Skip = 0,
+    X1 = 1,
+    X2 = 2,
+    X4 = 3,
+    X8 = 4,
+    X16 = 5,
+}
+
+impl Oversampling {
+    pub fn from_bits(bits: u8) -> Self {
+        match bits {
+            0 => Oversampling::Skip,
+            1 => Oversampling::X1,
+            2 => Oversampling::X2,
+            3 => Oversampling::X4,
+            4 => Oversampling::X8,
+            _ => Oversampling::X16,
+        }
+    }
+
+    // Measurement cycles spent on one conversion
+    pub fn cycles(self) -> u32 {
+        match self {
+            Oversampling::Skip => 0,
+            Oversampling::X1 => 1,
+            Oversampling::X2 => 2,
+            Oversampling::X4 => 4,
+            Oversampling::X8 => 8,
+            Oversampling::X16 => 16,
+        }
+    }
+}
+
+// IIR filter coefficient applied to temperature and pressure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum IirFilter {
+    Off = 0,
+    C1 = 1,
+    C3 = 2,
+    C7 = 3,
+    C15 = 4,
+    C31 = 5,
+    C63 = 6,
+    C127 = 7,
+}
+
+impl IirFilter {
+    pub fn from_bits(bits: u8) -> Self {
+        match bits & 0x07 {
+            0 => IirFilter::Off,
+            1 => IirFilter::C1,
+            2 => IirFilter::C3,
+            3 => IirFilter::C7,
+            4 => IirFilter::C15,
+            5 => IirFilter::C31,
+            6 => IirFilter::C63,
+            _ => IirFilter::C127,
+        }
+    }
+}
+
+// Measurement settings - create through Bme680Config::builder()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Bme680Config {
+    pub osrs_t: Oversampling,
+    pub osrs_p: Oversampling,
+    pub osrs_h: Oversampling,
+    pub filter: IirFilter,
+    pub heater_profile: u8,         // nb_conv
+    pub run_gas: bool,
+}
+
+impl Default for Bme680Config {
+    fn default() -> Self {
+        Bme680Config {
+            osrs_t: Oversampling::X8,
+            osrs_p: Oversampling::X4,
+            osrs_h: Oversampling::X2,
+            filter: IirFilter::C3,
+            heater_profile: 0,
+            run_gas: false,
+        }
+    }
+}
+
+impl Bme680Config {
+    pub fn builder() -> Bme680ConfigBuilder {
+        Bme680ConfigBuilder { config: Bme680Config::default() }
+    }
+
+    // Pressure and humidity compensation both need t_fine from the temperature channel
+    pub fn validate(&self) -> Result<(), BME680ExtError> {
+        let needs_temp = self.osrs_p != Oversampling::Skip || self.osrs_h != Oversampling::Skip;
+        if needs_temp && self.osrs_t == Oversampling::Skip {
+            return Err(BME680ExtError::InvalidConfig);
+        }
+        if self.heater_profile as usize >= HEATER_PROFILES {
+            return Err(BME680ExtError::InvalidConfig);
+        }
+        Ok(())
+    }
+}
+
+pub struct Bme680ConfigBuilder {
+    config: Bme680Config,
+}
+
+impl Bme680ConfigBuilder {
+    pub fn temperature_oversampling(mut self, os: Oversampling) -> Self {
+        self.config.osrs_t = os;
+        self
+    }
+
+    pub fn pressure_oversampling(mut self, os: Oversampling) -> Self {
+        self.config.osrs_p = os;
+        self
+    }
+
+    pub fn humidity_oversampling(mut self, os: Oversampling) -> Self {
+        self.config.osrs_h = os;
+        self
+    }
+
+    pub fn filter(mut self, filter: IirFilter) -> Self {
+        self.config.filter = filter;
+        self
+    }
+
+    pub fn heater_profile(mut self, index: u8) -> Self {
+        self.config.heater_profile = index;
+        self
+    }
+
+    pub fn run_gas(mut self, run_gas: bool) -> Self {
+        self.config.run_gas = run_gas;
+        self
+    }
+
+    pub fn build(self) -> Result<Bme680Config, BME680ExtError> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+// One coherent forced-mode conversion
+// temperature is the Bosch integer compensation in 0.01 degC, the snapshot keeps that resolution and
+// the temperature_c characteristic converts back to whole degC
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct Bme680Sample {
+    pub temperature: i32,       // 0.01 degC
+    pub pressure: Option<u32>,  // Pa, None if osrs_p is Skip
+    pub humidity: Option<u32>,  // 0.001 %RH, None if osrs_h is Skip
+    pub gas: Option<u32>,       // Ohm, None if gas is off or the reading isn't valid yet
+}
+
+// Heater target and how long to hold it before the gas conversion
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub struct HeaterProfile {
+    pub target_temp_c: u16,     // 200 - 400 degC
+    pub duration_ms: u16,       // 1 - 4032 ms
+}
+
+#[derive(Debug, Clone, Copy, Default, defmt::Format)]
+pub struct Bme680Calib {
+    pub par_t1: u16,
+    pub par_t2: i16,
+    pub par_t3: i8,
+
+    pub par_p1: u16,
+    pub par_p2: i16,
+    pub par_p3: i8,
+    pub par_p4: i16,
+    pub par_p5: i16,
+    pub par_p6: i8,
+    pub par_p7: i8,
+    pub par_p8: i16,
+    pub par_p9: i16,
+    pub par_p10: u8,
+
+    pub par_h1: u16,
+    pub par_h2: u16,
+    pub par_h3: i8,
+    pub par_h4: i8,
+    pub par_h5: i8,
+    pub par_h6: u8,
+    pub par_h7: i8,
+
+    pub par_g1: i8,
+    pub par_g2: i16,
+    pub par_g3: i8,
+    pub res_heat_range: u8,
+    pub res_heat_val: i8,
+    pub range_sw_err: i8,
+}
+
+impl Bme680Calib {
+
+    // Parse calibration from the three NVM blocks
+    // block_0 starts at 0x00, block_1 starts at 0x8A, block_2 starts at 0xE1
+    pub fn from_regs(block_0: &[u8; 5], block_1: &[u8; 23], block_2: &[u8; 14]) -> Self {
+        Bme680Calib {
+            par_t1: u16::from_le_bytes([block_2[8], block_2[9]]),               // 0xE9 / 0xEA
+            par_t2: i16::from_le_bytes([block_1[0], block_1[1]]),               // 0x8A / 0x8B
+            par_t3: block_1[2] as i8,                                           // 0x8C
+
+            par_p1: u16::from_le_bytes([block_1[4], block_1[5]]),               // 0x8E / 0x8F
+            par_p2: i16::from_le_bytes([block_1[6], block_1[7]]),               // 0x90 / 0x91
+            par_p3: block_1[8] as i8,                                           // 0x92
+            par_p4: i16::from_le_bytes([block_1[10], block_1[11]]),             // 0x94 / 0x95
+            par_p5: i16::from_le_bytes([block_1[12], block_1[13]]),             // 0x96 / 0x97
+            par_p6: block_1[15] as i8,                                          // 0x99
+            par_p7: block_1[14] as i8,                                          // 0x98
+            par_p8: i16::from_le_bytes([block_1[18], block_1[19]]),             // 0x9C / 0x9D
+            par_p9: i16::from_le_bytes([block_1[20], block_1[21]]),             // 0x9E / 0x9F
+            par_p10: block_1[22],                                               // 0xA0
+
+            par_h1: ((block_2[2] as u16) << 4) | (block_2[1] as u16 & 0x0F),    // 0xE3 / 0xE2<3:0>
+            par_h2: ((block_2[0] as u16) << 4) | (block_2[1] as u16 >> 4),      // 0xE1 / 0xE2<7:4>
+            par_h3: block_2[3] as i8,                                           // 0xE4
+            par_h4: block_2[4] as i8,                                           // 0xE5
+            par_h5: block_2[5] as i8,                                           // 0xE6
+            par_h6: block_2[6],                                                 // 0xE7
+            par_h7: block_2[7] as i8,                                           // 0xE8
+
+            par_g1: block_2[12] as i8,                                          // 0xED
+            par_g2: i16::from_le_bytes([block_2[10], block_2[11]]),             // 0xEB / 0xEC
+            par_g3: block_2[13] as i8,                                          // 0xEE
+            res_heat_range: (block_0[2] & 0x30) >> 4,                           // 0x02<5:4>
+            res_heat_val: block_0[0] as i8,                                     // 0x00
+            range_sw_err: (block_0[4] as i8) >> 4,                              // 0x04<7:4>, signed
+        }
+    }
+
+    // All-zero or all-ones NVM reads back as 0x0000 / 0xFFFF coefficients, real parts never have those
+    pub fn is_valid(&self) -> bool {
+        let blank = |val: u16| val == 0 || val == 0xFFFF;
+        !blank(self.par_t1) && !blank(self.par_t2 as u16) && !blank(self.par_p1) && !blank(self.par_p2 as u16)
+    }
+
+    // Returns t_fine from a raw 20 bit temperature reading
+    pub fn t_fine(&self, temp_adc: u32) -> i32 {
+        let var1 = ((temp_adc as i32) >> 3) - ((self.par_t1 as i32) << 1);
+        let var2 = (var1 * self.par_t2 as i32) >> 11;
+        let var3 = ((((var1 >> 1) * (var1 >> 1)) >> 12) * ((self.par_t3 as i32) << 4)) >> 14;
+        var2 + var3
+    }
+
+    // Temperature in 0.01 degC
+    pub fn compensate_temperature(&self, t_fine: i32) -> i32 {
+        ((t_fine * 5) + 128) >> 8
+    }
+
+    // Pressure in Pa from a raw 20 bit reading
+    // Done in i64 so large par_p10 values can't overflow the cubic term
+    pub fn compensate_pressure(&self, press_adc: u32, t_fine: i32) -> u32 {
+        let mut var1 = ((t_fine as i64) >> 1) - 64000;
+        let mut var2 = ((((var1 >> 2) * (var1 >> 2)) >> 11) * self.par_p6 as i64) >> 2;
+        var2 += (var1 * self.par_p5 as i64) << 1;
+        var2 = (var2 >> 2) + ((self.par_p4 as i64) << 16);
+        var1 = (((((var1 >> 2) * (var1 >> 2)) >> 13) * ((self.par_p3 as i64) << 5)) >> 3) + ((self.par_p2 as i64 * var1) >> 1);
+        var1 >>= 18;
+        var1 = ((32768 + var1) * self.par_p1 as i64) >> 15;
+        if var1 == 0 {
+            return 0;
+        }
+
+        let mut pressure_comp = 1048576 - press_adc as i64;
+        pressure_comp = (pressure_comp - (var2 >> 12)) * 3125;
+        pressure_comp = if pressure_comp >= (1 << 30) {
+            (pressure_comp / var1) << 1
+        } else {
+            (pressure_comp << 1) / var1
+        };
+
+        let var1 = (self.par_p9 as i64 * (((pressure_comp >> 3) * (pressure_comp >> 3)) >> 13)) >> 12;
+        let var2 = ((pressure_comp >> 2) * self.par_p8 as i64) >> 13;
+        let var3 = ((pressure_comp >> 8) * (pressure_comp >> 8) * (pressure_comp >> 8) * self.par_p10 as i64) >> 17;
+        pressure_comp += (var1 + var2 + var3 + ((self.par_p7 as i64) << 7)) >> 4;
+
+        pressure_comp.max(0) as u32
+    }
+
+    // Relative humidity in 0.001 %RH (50000 = 50 %RH)
+    pub fn compensate_humidity(&self, hum_adc: u16, t_fine: i32) -> u32 {
+        let temp_scaled = ((t_fine * 5) + 128) >> 8;
+        let par_h1 = self.par_h1 as i32;
+        let par_h2 = self.par_h2 as i32;
+
+        let var1 = (hum_adc as i32 - par_h1 * 16) - (((temp_scaled * self.par_h3 as i32) / 100) >> 1);
+        let var2 = (par_h2
+            * (((temp_scaled * self.par_h4 as i32) / 100)
+                + (((temp_scaled * ((temp_scaled * self.par_h5 as i32) / 100)) >> 6) / 100)
+                + (1 << 14)))
+            >> 10;
+        let var3 = var1 * var2;
+        let var4 = (((self.par_h6 as i32) << 7) + ((temp_scaled * self.par_h7 as i32) / 100)) >> 4;
+        let var5 = ((var3 >> 14) * (var3 >> 14)) >> 10;
+        let var6 = (var4 * var5) >> 1;
+        let calc_hum = (((var3 + var6) >> 10) * 1000) >> 12;
+
+        calc_hum.clamp(0, 100_000) as u32
+    }
+
+    // res_heat_x register value for a heater target, given the ambient temperature
+    // Targets outside the 200 - 400 degC the formula is specified for are clamped
+    pub fn heater_resistance(&self, target_temp_c: u16, amb_temp_c: i8) -> u8 {
+        let target = target_temp_c.clamp(200, 400) as i32;
+
+        let var1 = ((amb_temp_c as i32 * self.par_g3 as i32) / 1000) * 256;
+        let var2 = (self.par_g1 as i32 + 784) * (((((self.par_g2 as i32 + 154009) * target * 5) / 100) + 3276800) / 10);
+        let var3 = var1 + (var2 / 2);
+        let var4 = var3 / (self.res_heat_range as i32 + 4);
+        let var5 = (131 * self.res_heat_val as i32) + 65536;
+        let heatr_res_x100 = ((var4 / var5) - 250) * 34;
+
+        ((heatr_res_x100 + 50) / 100) as u8
+    }
+
+    // Gas resistance in ohms from the 10 bit ADC value and its range
+    pub fn compensate_gas(&self, gas_adc: u16, gas_range: u8) -> u32 {
+        let gas_range = (gas_range & 0x0F) as usize;
+
+        let var1 = ((1340 + 5 * self.range_sw_err as i64) * GAS_LOOKUP_1[gas_range] as i64) >> 16;
+        let var2 = ((gas_adc as i64) << 15) - 16777216 + var1;
+        let var3 = (GAS_LOOKUP_2[gas_range] as i64 * var1) >> 9;
+
+        ((var3 + (var2 >> 1)) / var2) as u32
+    }
+}
+
+// gas_wait_x register value - 6 bit duration with a 2 bit x1/x4/x16/x64 multiplier
+pub fn heater_duration(duration_ms: u16) -> u8 {
+    if duration_ms >= 0xFC0 {
+        return 0xFF;
+    }
+
+    let mut dur = duration_ms;
+    let mut factor = 0u8;
+    while dur > 0x3F {
+        dur /= 4;
+        factor += 1;
+    }
+
+    dur as u8 + factor * 64
+}
+
+pub struct BME680Ext {
+    pub bme: BME680,
+    pub calib: Bme680Calib,
+    t_fine: i32,
+    heater: [Option<HeaterProfile>; HEATER_PROFILES],
+    config: Bme680Config,
+}
+
+impl BME680Ext {
+
+    // Wrap an already initialized driver and load the calibration it doesn't expose
+    pub async fn new(mut bme: BME680) -> Result<Self, BME680ExtError> {
+        let calib = load_calib(&mut bme).await?;
+        d_info!("BME680 calibration: {}", calib);
+
+        let mut bme = BME680Ext {
+            bme,
+            calib,
+            t_fine: 0,
+            heater: [None; HEATER_PROFILES],
+            config: Bme680Config::default(),
+        };
+        bme.read_config().await?;
+
+        Ok(bme)
+    }
+
+    // Oversampling and filter go through chip_map, the gas registers are written directly
+    // Gas is only turned on if the selected heater profile has been programmed
+    pub async fn apply_config(&mut self, config: &Bme680Config) -> Result<(), BME680ExtError> {
+        config.validate()?;
+
+        self.bme.chip.write_field("osrs_t", config.osrs_t as u8).await.map_err(|_| BME680ExtError::Bus)?;
+        self.bme.chip.write_field("osrs_p", config.osrs_p as u8).await.map_err(|_| BME680ExtError::Bus)?;
+        self.bme.chip.write_field("osrs_h", config.osrs_h as u8).await.map_err(|_| BME680ExtError::Bus)?;
+        self.bme.chip.write_field("filter", config.filter as u8).await.map_err(|_| BME680ExtError::Bus)?;
+
+        if config.run_gas {
+            self.enable_gas(config.heater_profile, true).await?;
+        } else {
+            self.update_reg(CTRL_GAS_1, RUN_GAS, 0).await?;
+        }
+
+        self.config = *config;
+        d_info!("BME680 config: {}", self.config);
+
+        Ok(())
+    }
+
+    // Read back the settings currently on the chip
+    pub async fn read_config(&mut self) -> Result<Bme680Config, BME680ExtError> {
+        let chip = &mut self.bme.chip;
+        let ctrl_gas_1 = chip.read_reg(CTRL_GAS_1).await.map_err(|_| BME680ExtError::Bus)?;
+        let config = Bme680Config {
+            osrs_t: Oversampling::from_bits(chip.read_field("osrs_t").await.map_err(|_| BME680ExtError::Bus)?),
+            osrs_p: Oversampling::from_bits(chip.read_field("osrs_p").await.map_err(|_| BME680ExtError::Bus)?),
+            osrs_h: Oversampling::from_bits(chip.read_field("osrs_h").await.map_err(|_| BME680ExtError::Bus)?),
+            filter: IirFilter::from_bits(chip.read_field("filter").await.map_err(|_| BME680ExtError::Bus)?),
+            heater_profile: ctrl_gas_1 & NB_CONV_MASK,
+            run_gas: ctrl_gas_1 & RUN_GAS != 0,
+        };
+
+        self.config = config;
+        Ok(config)
+    }
+
+    pub fn config(&self) -> &Bme680Config {
+        &self.config
+    }
+
+    // Expected duration of one forced conversion including the heater, rounded up to ms
+    pub fn measurement_duration_ms(&self) -> u32 {
+        let cycles = self.config.osrs_t.cycles() + self.config.osrs_p.cycles() + self.config.osrs_h.cycles();
+
+        // Conversion cycles + TPH switching + gas measurement
+        let dur_us = cycles * 1963 + 477 * 4 + 477 * 5;
+        let mut dur_ms = (dur_us + 999) / 1000 + 1;    // +1 ms wake up
+
+        if self.config.run_gas {
+            if let Some(profile) = self.heater.get(self.config.heater_profile as usize).copied().flatten() {
+                dur_ms += profile.duration_ms as u32;
+            }
+        }
+
+        dur_ms
+    }
+
+    // Forced-mode conversion of every channel
+    // Waits the expected duration, then polls the status until the data is new and settled
+    pub async fn measure(&mut self) -> Result<Bme680Sample, BME680ExtError> {
+        self.bme.chip.write_field("mode", 1).await.map_err(|_| BME680ExtError::Bus)?;
+        Timer::after_millis(self.measurement_duration_ms() as u64).await;
+
+        // Status and data in one transaction so they always belong to the same conversion
+        let mut raw = [0u8; DATA_BURST_LEN];
+        let mut tries = 0;
+        loop {
+            self.bme.chip.read_regs(MEAS_STATUS_0, &mut raw).await.map_err(|_| BME680ExtError::Bus)?;
+            let status = raw[0];
+            if status & NEW_DATA_0 != 0 && status & (MEASURING | GAS_MEASURING) == 0 {
+                break;
+            }
+
+            tries += 1;
+            if tries >= MEAS_POLL_TRIES {
+                return Err(BME680ExtError::Timeout);
+            }
+            Timer::after_millis(MEAS_POLL_MS).await;
+        }
+
+        let sample = self.decode(&raw);
+        d_info!("BME680 sample: {}", sample);
+
+        Ok(sample)
+    }
+
+    // Decode a 0x1D..0x2B burst
+    // Skipped channels hold the 0x80000 / 0x8000 reset value, those aren't compensated
+    fn decode(&mut self, raw: &[u8; DATA_BURST_LEN]) -> Bme680Sample {
+        let press_adc = ((raw[2] as u32) << 12) | ((raw[3] as u32) << 4) | ((raw[4] as u32) >> 4);
+        let temp_adc = ((raw[5] as u32) << 12) | ((raw[6] as u32) << 4) | ((raw[7] as u32) >> 4);
+        let hum_adc = u16::from_be_bytes([raw[8], raw[9]]);
+        let gas_lsb = raw[14];
+        let gas_adc = ((raw[13] as u16) << 2) | ((gas_lsb as u16) >> 6);
+
+        self.t_fine = self.calib.t_fine(temp_adc);
+
+        let gas_ok = self.config.run_gas && gas_lsb & GAS_VALID_R != 0 && gas_lsb & HEAT_STAB_R != 0;
+        Bme680Sample {
+            temperature: self.calib.compensate_temperature(self.t_fine),
+            pressure: (self.config.osrs_p != Oversampling::Skip).then(|| self.calib.compensate_pressure(press_adc, self.t_fine)),
+            humidity: (self.config.osrs_h != Oversampling::Skip).then(|| self.calib.compensate_humidity(hum_adc, self.t_fine)),
+            gas: gas_ok.then(|| self.calib.compensate_gas(gas_adc, gas_lsb & 0x0F)),
+        }
+    }
+
+    // Program one of the 10 heater set-points
+    // The ambient temperature defaults to the last compensated reading
+    pub async fn set_heater_profile(&mut self, index: u8, profile: HeaterProfile, amb_temp_c: Option<i8>) -> Result<(), BME680ExtError> {
+        let slot = index as usize;
+        if slot >= HEATER_PROFILES || profile.duration_ms == 0 {
+            return Err(BME680ExtError::InvalidProfile);
+        }
+
+        let amb_temp_c = amb_temp_c.unwrap_or_else(|| self.ambient_temp_c());
+        let res_heat = self.calib.heater_resistance(profile.target_temp_c, amb_temp_c);
+        let gas_wait = heater_duration(profile.duration_ms);
+
+        self.bme.chip.write_reg(RES_HEAT_0 + index, res_heat).await.map_err(|_| BME680ExtError::Bus)?;
+        self.bme.chip.write_reg(GAS_WAIT_0 + index, gas_wait).await.map_err(|_| BME680ExtError::Bus)?;
+        self.heater[slot] = Some(profile);
+
+        d_info!("BME680 heater profile {}: {}, res_heat: {}, gas_wait: {}", index, profile, res_heat, gas_wait);
+        Ok(())
+    }
+
+    // Select the heater profile used by the next forced measurement and turn gas conversions on/off
+    pub async fn enable_gas(&mut self, index: u8, run_gas: bool) -> Result<(), BME680ExtError> {
+        if self.heater.get(index as usize).copied().flatten().is_none() {
+            return Err(BME680ExtError::InvalidProfile);
+        }
+
+        self.update_reg(CTRL_GAS_0, HEAT_OFF, 0).await?;
+        let run_gas_bit = if run_gas { RUN_GAS } else { 0 };
+        self.update_reg(CTRL_GAS_1, RUN_GAS | NB_CONV_MASK, run_gas_bit | index).await?;
+        self.config.heater_profile = index;
+        self.config.run_gas = run_gas;
+
+        Ok(())
+    }
+
+    // Last compensated temperature in degC, used for heater compensation
+    pub fn ambient_temp_c(&self) -> i8 {
+        let temp_comp = self.calib.compensate_temperature(self.t_fine);
+        (temp_comp / 100).clamp(i8::MIN as i32, i8::MAX as i32) as i8
+    }
+
+    // Read-modify-write of the bits in mask
+    async fn update_reg(&mut self, reg: u8, mask: u8, val: u8) -> Result<(), BME680ExtError> {
+        let current = self.bme.chip.read_reg(reg).await.map_err(|_| BME680ExtError::Bus)?;
+        self.bme.chip.write_reg(reg, (current & !mask) | (val & mask)).await.map_err(|_| BME680ExtError::Bus)
+    }
+
+}
+
+async fn load_calib(bme: &mut BME680) -> Result<Bme680Calib, BME680ExtError> {
+    let mut block_0 = [0u8; 5];
+    let mut block_1 = [0u8; 23];
+    let mut block_2 = [0u8; 14];
+    bme.chip.read_regs(CALIB_BLOCK_0, &mut block_0).await.map_err(|_| BME680ExtError::Bus)?;
+    bme.chip.read_regs(CALIB_BLOCK_1, &mut block_1).await.map_err(|_| BME680ExtError::Bus)?;
+    bme.chip.read_regs(CALIB_BLOCK_2, &mut block_2).await.map_err(|_| BME680ExtError::Bus)?;
+
+    Ok(Bme680Calib::from_regs(&block_0, &block_1, &block_2))
+}
@@ -0,0 +1,356 @@
+// LE Secure Connections pairing and bond storage
+//
+// Bonder is the SecurityHandler for every connection made through advertise(). Bonds (LTK, IRK, identity address)
+// and each peer's system attributes (CCCD values) are kept in RAM and mirrored to their own ConfigStore on two
+// reserved flash pages, see storage::BOND_STORE_START. SecurityHandler callbacks can't wait on flash, so changes
+// are queued for the bond_persist task.
+//
+// Store keys per bond slot: KEY_BOND + slot for the keys, KEY_SYS_ATTRS + 2 * slot (+ 1) for the system
+// attributes, split in two because a store record holds at most config_store::MAX_VALUE_LEN bytes.
+use core::cell::{Cell, RefCell};
+use core::sync::atomic::{AtomicBool, Ordering};
+use heapless::Vec;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+
+use nrf_softdevice::{raw, Softdevice};
+use nrf_softdevice::ble::{gatt_server, peripheral, Address, AddressType, Connection};
+use nrf_softdevice::ble::security::{IoCapabilities, PasskeyReply, SecurityHandler};
+use nrf_softdevice::ble::{EncryptionInfo, IdentityKey, IdentityResolutionKey, MasterId, SecurityMode};
+
+use crate::system::config_store::{ConfigStore, ConfigStoreError, MAX_VALUE_LEN};
+use crate::{d_info, warn};
+
+// Oldest bond is replaced when a new central bonds with every slot taken
+pub const MAX_BONDS: usize = 4;
+pub const SYS_ATTRS_LEN: usize = 2 * MAX_VALUE_LEN;
+
+const KEY_BOND: u8 = 0x10;
+const KEY_SYS_ATTRS: u8 = 0x20;
+
+// Bond encoding, bump when the layout changes - old records are then ignored
+const BOND_VERSION: u8 = 1;
+const BOND_LEN: usize = 54;
+const SYS_ATTRS_VERSION: u8 = 1;
+
+// Set once the current link is encrypted with the keys of a stored bond
+static LINK_BONDED: AtomicBool = AtomicBool::new(false);
+
+// System attributes handed to the SoftDevice for the current link, see ble_services::restore_subscriptions
+pub static RESTORED_SYS_ATTRS: Signal<ThreadModeRawMutex, Vec<u8, SYS_ATTRS_LEN>> = Signal::new();
+
+// CCCD value bit for notifications
+pub const CCCD_NOTIFY: u16 = 0x0001;
+
+// Slot changes waiting for bond_persist
+static BOND_WRITES: Channel<ThreadModeRawMutex, BondWrite, { 2 * MAX_BONDS }> = Channel::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PairingMode {
+    JustWorks,          // No MITM protection, nothing to display or enter
+    PasskeyDisplay,     // Passkey is logged, the central's user types it in
+    PasskeyEntry,       // Central displays a passkey, handed back through Bonder::enter_passkey
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum BondWrite {
+    Keys(usize),
+    SysAttrs(usize),
+}
+
+#[derive(Clone, Copy)]
+struct Bond {
+    age: u32,                   // Bond counter at pairing time, lowest is replaced first
+    master_id: MasterId,
+    key: EncryptionInfo,
+    peer_id: IdentityKey,
+}
+
+impl Bond {
+    // [age u32, ediv u16, rand 8, ltk 16, flags u8, irk 16, addr type u8, addr 6]
+    fn to_bytes(self) -> [u8; BOND_LEN] {
+        let mut buf = [0u8; BOND_LEN];
+        buf[0..4].copy_from_slice(&self.age.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.master_id.ediv.to_le_bytes());
+        buf[6..14].copy_from_slice(&self.master_id.rand);
+        buf[14..30].copy_from_slice(&self.key.ltk);
+        buf[30] = self.key.flags;
+        buf[31..47].copy_from_slice(&self.peer_id.irk.as_raw().irk);
+        buf[47] = self.peer_id.addr.address_type() as u8;
+        buf[48..54].copy_from_slice(&self.peer_id.addr.bytes());
+        buf
+    }
+
+    fn from_bytes(buf: &[u8; BOND_LEN]) -> Option<Self> {
+        let mut rand = [0u8; 8];
+        rand.copy_from_slice(&buf[6..14]);
+        let mut ltk = [0u8; 16];
+        ltk.copy_from_slice(&buf[14..30]);
+        let mut irk = [0u8; 16];
+        irk.copy_from_slice(&buf[31..47]);
+        let mut addr = [0u8; 6];
+        addr.copy_from_slice(&buf[48..54]);
+
+        let address_type = AddressType::try_from(buf[47]).ok()?;
+        Some(Bond {
+            age: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            master_id: MasterId { ediv: u16::from_le_bytes([buf[4], buf[5]]), rand },
+            key: EncryptionInfo { ltk, flags: buf[30] },
+            peer_id: IdentityKey {
+                irk: IdentityResolutionKey::from_raw(raw::ble_gap_irk_t { irk }),
+                addr: Address::new(address_type, addr),
+            },
+        })
+    }
+}
+
+pub struct Bonder {
+    mode: PairingMode,
+    bonds: RefCell<[Option<Bond>; MAX_BONDS]>,
+    sys_attrs: RefCell<[Vec<u8, SYS_ATTRS_LEN>; MAX_BONDS]>,
+    passkey_reply: RefCell<Option<PasskeyReply>>,
+    next_age: Cell<u32>,
+}
+
+impl Bonder {
+    pub const fn new(mode: PairingMode) -> Self {
+        Bonder {
+            mode,
+            bonds: RefCell::new([None; MAX_BONDS]),
+            sys_attrs: RefCell::new([const { Vec::new() }; MAX_BONDS]),
+            passkey_reply: RefCell::new(None),
+            next_age: Cell::new(0),
+        }
+    }
+
+    // Load every stored bond, call before advertising
+    pub async fn restore(&self, store: &mut ConfigStore) -> Result<(), ConfigStoreError> {
+        let mut restored = 0;
+        for slot in 0..MAX_BONDS {
+            let mut buf = [0u8; BOND_LEN];
+            let bond = match store.read(KEY_BOND + slot as u8, BOND_VERSION, &mut buf).await? {
+                Some(BOND_LEN) => Bond::from_bytes(&buf),
+                _ => None,
+            };
+            let Some(bond) = bond else {
+                continue;
+            };
+
+            let mut sys_attrs = Vec::new();
+            for chunk in 0..2 {
+                let mut buf = [0u8; MAX_VALUE_LEN];
+                if let Some(len) = store.read(KEY_SYS_ATTRS + 2 * slot as u8 + chunk, SYS_ATTRS_VERSION, &mut buf).await? {
+                    let _ = sys_attrs.extend_from_slice(&buf[..len]);
+                }
+            }
+
+            self.next_age.set(self.next_age.get().max(bond.age.wrapping_add(1)));
+            self.bonds.borrow_mut()[slot] = Some(bond);
+            self.sys_attrs.borrow_mut()[slot] = sys_attrs;
+            restored += 1;
+        }
+
+        d_info!("Restored {} bonds", restored);
+        Ok(())
+    }
+
+    // Answer a PasskeyEntry pairing with the passkey shown on the central, ASCII digits
+    pub fn enter_passkey(&self, passkey: &[u8; 6]) -> bool {
+        match self.passkey_reply.borrow_mut().take() {
+            Some(reply) => reply.reply(Some(passkey)).is_ok(),
+            None => false,
+        }
+    }
+
+    // Forget every bond, in RAM and in flash
+    pub fn clear(&self) {
+        for slot in 0..MAX_BONDS {
+            if self.bonds.borrow_mut()[slot].take().is_some() {
+                self.sys_attrs.borrow_mut()[slot].clear();
+                queue_write(BondWrite::Keys(slot));
+                queue_write(BondWrite::SysAttrs(slot));
+            }
+        }
+    }
+
+    // Slot of the bond this address resolves to
+    fn find(&self, addr: Address) -> Option<usize> {
+        self.bonds.borrow().iter().position(|bond| bond.is_some_and(|bond| bond.peer_id.is_match(addr)))
+    }
+
+    // Same peer, else a free slot, else the oldest bond
+    fn slot_for(&self, peer_id: &IdentityKey) -> usize {
+        let bonds = self.bonds.borrow();
+        if let Some(slot) = bonds.iter().position(|bond| bond.is_some_and(|bond| bond.peer_id.addr == peer_id.addr)) {
+            return slot;
+        }
+        if let Some(slot) = bonds.iter().position(Option::is_none) {
+            return slot;
+        }
+        (0..MAX_BONDS).min_by_key(|&slot| bonds[slot].map_or(0, |bond| bond.age)).unwrap_or(0)
+    }
+}
+
+impl SecurityHandler for Bonder {
+    fn io_capabilities(&self) -> IoCapabilities {
+        match self.mode {
+            PairingMode::JustWorks => IoCapabilities::None,
+            PairingMode::PasskeyDisplay => IoCapabilities::DisplayOnly,
+            PairingMode::PasskeyEntry => IoCapabilities::KeyboardOnly,
+        }
+    }
+
+    fn can_bond(&self, _conn: &Connection) -> bool {
+        true
+    }
+
+    fn display_passkey(&self, passkey: &[u8; 6]) {
+        d_info!("Pairing passkey: {=[u8]:a}", passkey.as_slice());
+    }
+
+    fn enter_passkey(&self, reply: PasskeyReply) {
+        d_info!("Pairing passkey requested, waiting for Bonder::enter_passkey");
+        *self.passkey_reply.borrow_mut() = Some(reply);
+    }
+
+    fn on_security_update(&self, conn: &Connection, security_mode: SecurityMode) {
+        let bonded = security_mode != SecurityMode::Open
+            && security_mode != SecurityMode::NoAccess
+            && self.find(conn.peer_address()).is_some();
+        d_info!("Link security: {:?}, bonded: {}", security_mode, bonded);
+        LINK_BONDED.store(bonded, Ordering::Relaxed);
+    }
+
+    fn on_bonded(&self, _conn: &Connection, master_id: MasterId, key: EncryptionInfo, peer_id: IdentityKey) {
+        let slot = self.slot_for(&peer_id);
+        let age = self.next_age.get();
+        self.next_age.set(age.wrapping_add(1));
+
+        d_info!("Bonded, slot {}", slot);
+        self.bonds.borrow_mut()[slot] = Some(Bond { age, master_id, key, peer_id });
+        self.sys_attrs.borrow_mut()[slot].clear();
+        queue_write(BondWrite::Keys(slot));
+        queue_write(BondWrite::SysAttrs(slot));
+        LINK_BONDED.store(true, Ordering::Relaxed);
+    }
+
+    fn get_key(&self, conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
+        // LE Secure Connections bonds all have a zero master ID, the resolved peer address tells them apart
+        let bonds = self.bonds.borrow();
+        let slot = self.find(conn.peer_address())
+            .or_else(|| bonds.iter().position(|bond| bond.is_some_and(|bond| bond.master_id == master_id)))?;
+        bonds[slot].map(|bond| bond.key)
+    }
+
+    // Called as the link closes - keeps the peer's CCCDs for its next connection
+    fn save_sys_attrs(&self, conn: &Connection) {
+        let Some(slot) = self.find(conn.peer_address()) else {
+            return;
+        };
+
+        let mut buf = [0u8; SYS_ATTRS_LEN];
+        match gatt_server::get_sys_attrs(conn, &mut buf) {
+            Ok(len) => {
+                let mut sys_attrs = self.sys_attrs.borrow_mut();
+                if sys_attrs[slot].as_slice() != &buf[..len] {
+                    sys_attrs[slot] = Vec::from_slice(&buf[..len]).unwrap_or_default();
+                    queue_write(BondWrite::SysAttrs(slot));
+                }
+            }
+            Err(e) => warn!("System attributes not saved: {:?}", e),
+        }
+    }
+
+    // Called when the SoftDevice asks for the peer's system attributes, a bonded peer gets its saved CCCDs back
+    fn load_sys_attrs(&self, conn: &Connection) {
+        let sys_attrs = self.sys_attrs.borrow();
+        let attrs = self.find(conn.peer_address())
+            .map(|slot| sys_attrs[slot].as_slice())
+            .filter(|attrs| !attrs.is_empty());
+
+        match gatt_server::set_sys_attrs(conn, attrs) {
+            Ok(()) => {
+                if let Some(attrs) = attrs {
+                    d_info!("System attributes restored, {} bytes", attrs.len());
+                    RESTORED_SYS_ATTRS.signal(Vec::from_slice(attrs).unwrap_or_default());
+                }
+            }
+            Err(e) => warn!("System attributes not restored: {:?}", e),
+        }
+    }
+}
+
+// (handle, value) of every 2 byte attribute in a system attributes blob
+// SoftDevice layout: [handle u16, len u16, value] per attribute, CRC16 at the end
+pub fn cccd_values(sys_attrs: &[u8]) -> impl Iterator<Item = (u16, u16)> + '_ {
+    let mut rest = sys_attrs;
+    core::iter::from_fn(move || {
+        while let [h0, h1, l0, l1, tail @ ..] = rest {
+            let handle = u16::from_le_bytes([*h0, *h1]);
+            let len = u16::from_le_bytes([*l0, *l1]) as usize;
+            if len > tail.len() {
+                return None;
+            }
+            let (value, next) = tail.split_at(len);
+            rest = next;
+            if let &[v0, v1] = value {
+                return Some((handle, u16::from_le_bytes([v0, v1])));
+            }
+        }
+        None
+    })
+}
+
+fn queue_write(write: BondWrite) {
+    if BOND_WRITES.try_send(write).is_err() {
+        warn!("Bond write dropped: {}", write);
+    }
+}
+
+// Encrypted with the keys of a stored bond - required for config writes
+pub fn link_bonded() -> bool {
+    LINK_BONDED.load(Ordering::Relaxed)
+}
+
+// Connectable advertising with pairing and bonding through the bonder
+pub async fn advertise(bonder: &'static Bonder, adv_data: &[u8], scan_data: &[u8]) -> Result<Connection, peripheral::AdvertiseError> {
+    // BLEWrapper keeps the Softdevice handle to itself, it is a singleton once enabled
+    let sd = unsafe { Softdevice::steal() };
+    let adv = peripheral::ConnectableAdvertisement::ScannableUndirected { adv_data, scan_data };
+
+    LINK_BONDED.store(false, Ordering::Relaxed);
+    RESTORED_SYS_ATTRS.reset();
+    peripheral::advertise_pairable(sd, adv, &peripheral::Config::default(), bonder).await
+}
+
+// Mirror bond changes to flash
+#[embassy_executor::task]
+pub async fn bond_persist(bonder: &'static Bonder, mut store: ConfigStore) {
+    loop {
+        let write = BOND_WRITES.receive().await;
+        let result = match write {
+            BondWrite::Keys(slot) => {
+                let bond = bonder.bonds.borrow()[slot];
+                match bond {
+                    Some(bond) => store.write(KEY_BOND + slot as u8, BOND_VERSION, &bond.to_bytes()).await,
+                    None => store.write(KEY_BOND + slot as u8, BOND_VERSION, &[]).await,
+                }
+            }
+            BondWrite::SysAttrs(slot) => {
+                let sys_attrs = bonder.sys_attrs.borrow()[slot].clone();
+                let (first, second) = sys_attrs.split_at(sys_attrs.len().min(MAX_VALUE_LEN));
+                match store.write(KEY_SYS_ATTRS + 2 * slot as u8, SYS_ATTRS_VERSION, first).await {
+                    Ok(()) => store.write(KEY_SYS_ATTRS + 2 * slot as u8 + 1, SYS_ATTRS_VERSION, second).await,
+                    Err(e) => Err(e),
+                }
+            }
+        };
+
+        match result {
+            Ok(()) => d_info!("Saved bond {}", write),
+            Err(e) => warn!("Saving bond {} failed: {:?}", write, e),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Advertising whose data can be replaced while it runs
+// Used by the BTHome and beacon broadcasts, and by ble_bme_char to refresh its connectable advertising in place.
+// The SoftDevice has a single advertising set, so one Broadcaster per binary.
+// The SoftDevice reads the data from RAM while advertising, so updates alternate between two buffers
+use core::ptr;
+use static_cell::StaticCell;
+
+use nrf_softdevice::{raw, RawError};
+
+use crate::system::advertising::LEGACY_ADV_LEN;
+
+// Handle of the only advertising set, once it has been configured
+const ADV_SET_HANDLE: u8 = 0;
+
+struct AdvBuffer {
+    adv_data: [u8; LEGACY_ADV_LEN],
+    scan_data: [u8; LEGACY_ADV_LEN],
+}
+
+pub struct Broadcaster {
+    handle: u8,
+    buffers: &'static mut [AdvBuffer; 2],
+    active: usize,
+}
+
+// Buffers handed to the SoftDevice, they must outlive the advertising
+static ADV_BUFFERS: StaticCell<[AdvBuffer; 2]> = StaticCell::new();
+
+impl Broadcaster {
+    // Panics if called twice, see ADV_BUFFERS
+    pub fn take() -> Self {
+        let empty = || AdvBuffer { adv_data: [0; LEGACY_ADV_LEN], scan_data: [0; LEGACY_ADV_LEN] };
+        Broadcaster {
+            handle: raw::BLE_GAP_ADV_SET_HANDLE_NOT_SET as u8,
+            buffers: ADV_BUFFERS.init([empty(), empty()]),
+            active: 1,
+        }
+    }
+
+    // Non-connectable, non-scannable advertising
+    pub fn start(&mut self, adv_data: &[u8], interval_ms: u32) -> Result<(), RawError> {
+        let mut params: raw::ble_gap_adv_params_t = unsafe { core::mem::zeroed() };
+        params.properties.type_ = raw::BLE_GAP_ADV_TYPE_NONCONNECTABLE_NONSCANNABLE_UNDIRECTED as u8;
+        params.primary_phy = raw::BLE_GAP_PHY_1MBPS as u8;
+        params.interval = interval_ms * 1000 / 625;     // 0.625 ms units
+
+        self.configure(adv_data, &[], &params)?;
+        let ret = unsafe { raw::sd_ble_gap_adv_start(self.handle, raw::BLE_CONN_CFG_TAG_DEFAULT as u8) };
+        RawError::convert(ret)
+    }
+
+    // Swap in new data without stopping
+    pub fn update(&mut self, adv_data: &[u8]) -> Result<(), RawError> {
+        self.configure(adv_data, &[], ptr::null())
+    }
+
+    // Swap in new data for scannable advertising started elsewhere, e.g. bonding::advertise, without stopping it
+    // Fails with InvalidState if that advertising isn't running
+    pub fn update_running(&mut self, adv_data: &[u8], scan_data: &[u8]) -> Result<(), RawError> {
+        self.handle = ADV_SET_HANDLE;
+        self.configure(adv_data, scan_data, ptr::null())
+    }
+
+    fn configure(&mut self, adv_data: &[u8], scan_data: &[u8], params: *const raw::ble_gap_adv_params_t) -> Result<(), RawError> {
+        let next = 1 - self.active;
+        let buffer = &mut self.buffers[next];
+        let adv_len = adv_data.len().min(LEGACY_ADV_LEN);
+        let scan_len = scan_data.len().min(LEGACY_ADV_LEN);
+        buffer.adv_data[..adv_len].copy_from_slice(&adv_data[..adv_len]);
+        buffer.scan_data[..scan_len].copy_from_slice(&scan_data[..scan_len]);
+
+        // Non-scannable advertising has no scan response
+        let scan_rsp_data = match scan_len {
+            0 => raw::ble_data_t { p_data: ptr::null_mut(), len: 0 },
+            _ => raw::ble_data_t { p_data: buffer.scan_data.as_mut_ptr(), len: scan_len as u16 },
+        };
+        let data = raw::ble_gap_adv_data_t {
+            adv_data: raw::ble_data_t { p_data: buffer.adv_data.as_mut_ptr(), len: adv_len as u16 },
+            scan_rsp_data,
+        };
+        let ret = unsafe { raw::sd_ble_gap_adv_set_configure(&mut self.handle, &data, params) };
+        RawError::convert(ret)?;
+
+        self.active = next;
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+// BTHome v2 sensor broadcasts for gateways that only scan - https://bthome.io/format/
+//
+// Framing and AES-CCM live in primer_logic so they can be tested on the host, the objects need the
+// snapshot and the cipher needs the SoftDevice, both stay here
+//
+// The counter must never repeat for a key, so it is handed out in blocks reserved in the config store
+// before use - a reset skips the rest of the block instead of reusing it.
+pub use primer_logic::bthome::*;
+
+use heapless::Vec;
+
+use embassy_time::Timer;
+
+use nrf_softdevice::{raw, RawError, Softdevice};
+use nrf_softdevice::ble;
+
+use crate::system::advertising::{AdvLimit, AdvertisementBuilder, FLAGS_GENERAL, LEGACY_ADV_LEN};
+use crate::system::broadcaster::Broadcaster;
+use crate::system::config_store::{ConfigStore, ConfigStoreError, KEY_BTHOME_COUNTER, KEY_BTHOME_KEY};
+use crate::system::state::{self, SensorSnapshot};
+use crate::{d_info, warn};
+
+const COUNTER_BLOCK: u32 = 4096;            // Counters reserved per config store write
+const KEY_VERSION: u8 = 1;
+const COUNTER_VERSION: u8 = 1;
+
+// AES through the SoftDevice ECB, the peripheral belongs to it while it is enabled
+pub struct SoftdeviceAes {
+    key: [u8; 16],
+}
+
+impl SoftdeviceAes {
+    pub const fn new(key: [u8; 16]) -> Self {
+        SoftdeviceAes { key }
+    }
+}
+
+impl BlockCipher for SoftdeviceAes {
+    type Error = RawError;
+
+    fn encrypt_block(&self, block: &mut [u8; 16]) -> Result<(), RawError> {
+        let mut ecb = raw::nrf_ecb_hal_data_t { key: self.key, cleartext: *block, ciphertext: [0; 16] };
+        let ret = unsafe { raw::sd_ecb_block_encrypt(&mut ecb) };
+        RawError::convert(ret)?;
+        *block = ecb.ciphertext;
+        Ok(())
+    }
+}
+
+// Objects for every channel sampled so far, packet_id lets receivers drop repeats
+pub fn encode_objects(snapshot: &SensorSnapshot, packet_id: u8) -> Vec<u8, MAX_OBJECTS_LEN> {
+    let mut objects = Vec::new();
+    // Sized for every object, the pushes can't fail
+    let _ = objects.extend_from_slice(&[OBJ_PACKET_ID, packet_id]);
+    if let Some(level) = snapshot.battery_level() {
+        let _ = objects.extend_from_slice(&[OBJ_BATTERY, level.min(100)]);
+    }
+    if let Some(temperature) = snapshot.temperature() {
+        let temperature = temperature.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        let _ = objects.push(OBJ_TEMPERATURE);
+        let _ = objects.extend_from_slice(&temperature.to_le_bytes());
+    }
+    if let Some(humidity) = snapshot.humidity() {
+        let humidity = (humidity / 10).min(u16::MAX as u32) as u16;
+        let _ = objects.push(OBJ_HUMIDITY);
+        let _ = objects.extend_from_slice(&humidity.to_le_bytes());
+    }
+    if let Some(pressure) = snapshot.pressure() {
+        // Pa is 0.01 hPa already
+        let pressure = pressure.min(0x00FF_FFFF).to_le_bytes();
+        let _ = objects.extend_from_slice(&[OBJ_PRESSURE, pressure[0], pressure[1], pressure[2]]);
+    }
+    objects
+}
+
+// The broadcast key from the config store, generated and saved on first use
+// Logged at boot so it can be entered on the gateway
+pub async fn load_or_create_key(store: &mut ConfigStore) -> Result<[u8; 16], ConfigStoreError> {
+    let mut key = [0u8; 16];
+    if store.read(KEY_BTHOME_KEY, KEY_VERSION, &mut key).await? == Some(key.len()) {
+        d_info!("BTHome key: {=[u8]:02x}", key.as_slice());
+        return Ok(key);
+    }
+
+    // The SoftDevice RNG pool refills in the background
+    loop {
+        let ret = unsafe { raw::sd_rand_application_vector_get(key.as_mut_ptr(), key.len() as u8) };
+        if RawError::convert(ret).is_ok() {
+            break;
+        }
+        Timer::after_millis(10).await;
+    }
+
+    store.write(KEY_BTHOME_KEY, KEY_VERSION, &key).await?;
+    d_info!("BTHome key generated: {=[u8]:02x}", key.as_slice());
+    Ok(key)
+}
+
+// Encryption counters, unused ones are skipped rather than reused after a reset
+struct Counter {
+    next: u32,
+    reserved_until: u32,
+}
+
+impl Counter {
+    async fn restore(store: &mut ConfigStore) -> Result<Self, ConfigStoreError> {
+        let mut buf = [0u8; 4];
+        let next = match store.read(KEY_BTHOME_COUNTER, COUNTER_VERSION, &mut buf).await? {
+            Some(4) => u32::from_le_bytes(buf),
+            _ => 0,
+        };
+        Ok(Counter { next, reserved_until: next })
+    }
+
+    async fn take(&mut self, store: &mut ConfigStore) -> Result<u32, ConfigStoreError> {
+        if self.next == self.reserved_until {
+            let reserved_until = self.next.saturating_add(COUNTER_BLOCK);
+            store.write(KEY_BTHOME_COUNTER, COUNTER_VERSION, &reserved_until.to_le_bytes()).await?;
+            self.reserved_until = reserved_until;
+        }
+        let counter = self.next;
+        self.next = self.next.saturating_add(1);
+        Ok(counter)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct BroadcastConfig {
+    pub interval_ms: u32,       // Advertising interval, 20 ms - 10.24 s
+    pub update_ms: u64,         // How often the readings are re-encoded
+    pub encrypted: bool,        // AES-CCM with the key from load_or_create_key
+}
+
+// Flags + BTHome service data, the readings fill the legacy payload so there is no room for a name
+fn build_adv(service_data: &[u8]) -> Option<Vec<u8, LEGACY_ADV_LEN>> {
+    let adv = AdvertisementBuilder::new(AdvLimit::Legacy)
+        .flags(FLAGS_GENERAL)
+        .service_data_16(BTHOME_UUID, service_data)
+        .build();
+    match adv {
+        Ok(adv) => Vec::from_slice(&adv.adv_data).ok(),
+        Err(e) => {
+            warn!("BTHome advertisement not built: {:?}", e);
+            None
+        }
+    }
+}
+
+// Everything an encrypted frame needs, the store only matters for the key and counter
+struct Encryption {
+    cipher: SoftdeviceAes,
+    counter: Counter,
+    store: ConfigStore,
+}
+
+impl Encryption {
+    async fn start(mut store: ConfigStore) -> Result<Self, ConfigStoreError> {
+        let key = load_or_create_key(&mut store).await?;
+        let counter = Counter::restore(&mut store).await?;
+        Ok(Encryption { cipher: SoftdeviceAes::new(key), counter, store })
+    }
+}
+
+// Broadcast the latest readings until reset
+// store holds the key and the encryption counter, it is only needed when config.encrypted is set
+#[embassy_executor::task]
+pub async fn bthome_broadcast(config: BroadcastConfig, store: Option<ConfigStore>) {
+    // BLEWrapper keeps the Softdevice handle to itself, it is a singleton once enabled
+    let sd = unsafe { Softdevice::steal() };
+
+    let mut mac = ble::get_address(sd).bytes();
+    mac.reverse();
+
+    let mut encryption = match (config.encrypted, store) {
+        (false, _) => None,
+        (true, None) => {
+            warn!("BTHome config store unavailable, broadcasting unencrypted");
+            None
+        }
+        (true, Some(store)) => match Encryption::start(store).await {
+            Ok(encryption) => Some(encryption),
+            Err(e) => {
+                warn!("BTHome key or counter unavailable, broadcasting unencrypted: {:?}", e);
+                None
+            }
+        },
+    };
+
+    let mut broadcaster = Broadcaster::take();
+    let mut started = false;
+    let mut packet_id: u8 = 0;
+
+    loop {
+        let objects = encode_objects(&state::snapshot(), packet_id);
+        let service_data = match &mut encryption {
+            Some(enc) => match enc.counter.take(&mut enc.store).await {
+                Ok(count) => match encrypted_service_data(&objects, &enc.cipher, mac, count) {
+                    Ok(data) => Some(data),
+                    Err(e) => {
+                        warn!("BTHome encryption failed, skipping update: {:?}", e);
+                        None
+                    }
+                },
+                Err(e) => {
+                    warn!("BTHome counter not reserved, skipping update: {:?}", e);
+                    None
+                }
+            },
+            None => Some(service_data(&objects)),
+        };
+
+        if let Some(adv_data) = service_data.and_then(|data| build_adv(&data)) {
+            let result = if started {
+                broadcaster.update(&adv_data)
+            } else {
+                broadcaster.start(&adv_data, config.interval_ms)
+            };
+            match result {
+                Ok(()) => {
+                    started = true;
+                    d_info!("BTHome packet {} broadcast, {} bytes", packet_id, adv_data.len());
+                }
+                Err(e) => warn!("BTHome advertising failed: {:?}", e),
+            }
+        }
+
+        packet_id = packet_id.wrapping_add(1);
+        Timer::after_millis(config.update_ms).await;
+    }
+}
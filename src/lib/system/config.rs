@@ -0,0 +1,75 @@
+// Runtime configuration, written over BLE through the config service
+// Sensor tasks and characteristic updaters pick up changes without a reboot
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::watch::Watch;
+
+use crate::system::bme680_ext::{Bme680Config, Oversampling};
+
+// Accepted ranges for the periods, in ms
+pub const SAMPLE_MS_RANGE: (u32, u32) = (100, 3_600_000);
+pub const NOTIFY_MS_RANGE: (u32, u32) = (100, 3_600_000);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ConfigError {
+    SamplePeriod,
+    NotifyPeriod,
+    Oversampling,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct RuntimeConfig {
+    pub sample_ms: u32,         // BME680 sample period
+    pub notify_ms: u32,         // Periodic update period / longest gap between change-driven updates
+    pub oversampling: [u8; 3],  // osrs_t, osrs_p, osrs_h register codes (0 = skip .. 5 = x16)
+}
+
+impl RuntimeConfig {
+    pub const DEFAULT: RuntimeConfig = RuntimeConfig {
+        sample_ms: 500,
+        notify_ms: 30_000,
+        oversampling: [Oversampling::X8 as u8, Oversampling::X4 as u8, Oversampling::X2 as u8],
+    };
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.sample_ms < SAMPLE_MS_RANGE.0 || self.sample_ms > SAMPLE_MS_RANGE.1 {
+            return Err(ConfigError::SamplePeriod);
+        }
+        if self.notify_ms < NOTIFY_MS_RANGE.0 || self.notify_ms > NOTIFY_MS_RANGE.1 {
+            return Err(ConfigError::NotifyPeriod);
+        }
+        self.apply_to(&Bme680Config::default())?;
+        Ok(())
+    }
+
+    // Oversampling on top of an existing BME680 config, filter and gas settings are kept
+    pub fn apply_to(&self, base: &Bme680Config) -> Result<Bme680Config, ConfigError> {
+        if self.oversampling.iter().any(|&bits| bits > Oversampling::X16 as u8) {
+            return Err(ConfigError::Oversampling);
+        }
+
+        let [osrs_t, osrs_p, osrs_h] = self.oversampling;
+        let config = Bme680Config {
+            osrs_t: Oversampling::from_bits(osrs_t),
+            osrs_p: Oversampling::from_bits(osrs_p),
+            osrs_h: Oversampling::from_bits(osrs_h),
+            ..*base
+        };
+        config.validate().map_err(|_| ConfigError::Oversampling)?;
+        Ok(config)
+    }
+}
+
+// Receivers for bme_update and config_store::config_persist, updaters read the current value
+pub const CONFIG_RECEIVERS: usize = 2;
+pub static CONFIG_WATCH: Watch<ThreadModeRawMutex, RuntimeConfig, CONFIG_RECEIVERS> = Watch::new_with(RuntimeConfig::DEFAULT);
+
+pub fn current() -> RuntimeConfig {
+    CONFIG_WATCH.try_get().unwrap_or(RuntimeConfig::DEFAULT)
+}
+
+// Validate and publish a new configuration
+pub fn set(config: RuntimeConfig) -> Result<(), ConfigError> {
+    config.validate()?;
+    CONFIG_WATCH.sender().send(config);
+    Ok(())
+}
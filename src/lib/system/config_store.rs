@@ -0,0 +1,381 @@
+// Small key-value store for device configuration on two reserved flash pages
+//
+// Page layout: 8 byte header [magic u16, format u16, generation u32] followed by an append-only record log
+// Record layout: [key u8, version u8, len u8, 0xFF, crc32 u32] + value padded to 4 bytes
+//   - the last valid record for a key wins, records failing the CRC are skipped
+//   - version belongs to the caller's value encoding, records with an unexpected version are ignored
+//
+// When the active page fills up, the latest record of every other key is copied to the other page, followed by
+// the record being written, and the page header goes last with generation + 1. A reset mid-compaction leaves
+// the old page active with the old value, and the pages alternate so both wear at the same rate.
+// A torn record header (reset mid-write) ends the log. The flash after it is no longer erased, so the next
+// write compacts to the other page instead of appending there.
+use heapless::Vec;
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::system::config::{self, RuntimeConfig, CONFIG_WATCH};
+use crate::system::storage::{PAGE_SIZE, SharedFlash, crc32};
+use crate::{d_info, warn};
+
+const MAGIC: u16 = 0xC0F1;
+const FORMAT: u16 = 1;
+const PAGE_HEADER_LEN: u32 = 8;
+const RECORD_HEADER_LEN: usize = 8;
+const ERASED_WORD: u32 = 0xFFFF_FFFF;
+
+pub const MAX_VALUE_LEN: usize = 64;
+const MAX_KEYS: usize = 32;
+
+// Keys used by the firmware, 0xFF is reserved for erased flash
+pub const KEY_RUNTIME_CONFIG: u8 = 0x01;
+pub const KEY_BTHOME_KEY: u8 = 0x02;
+pub const KEY_BTHOME_COUNTER: u8 = 0x03;
+pub const KEY_BOOT_COUNT: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ConfigStoreError {
+    Flash,
+    InvalidKey,
+    TooLarge,
+    TooManyKeys,
+}
+
+pub struct ConfigStore {
+    flash: &'static SharedFlash,
+    pages: [u32; 2],        // Start address of each page
+    active: usize,          // Index into pages
+    generation: u32,
+    write_offset: u32,      // Next free byte in the active page
+    torn: bool,             // Log ends on a torn header, flash at write_offset isn't erased
+}
+
+struct RecordHeader {
+    key: u8,
+    version: u8,
+    len: u8,
+    crc: u32,
+}
+
+impl RecordHeader {
+    fn parse(bytes: &[u8; RECORD_HEADER_LEN]) -> Self {
+        RecordHeader {
+            key: bytes[0],
+            version: bytes[1],
+            len: bytes[2],
+            crc: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+
+    fn crc(key: u8, version: u8, value: &[u8]) -> u32 {
+        let mut buf = [0u8; 3 + MAX_VALUE_LEN];
+        buf[0] = key;
+        buf[1] = version;
+        buf[2] = value.len() as u8;
+        buf[3..3 + value.len()].copy_from_slice(value);
+        crc32(&buf[..3 + value.len()])
+    }
+
+    fn record_len(len: u8) -> u32 {
+        (RECORD_HEADER_LEN + (len as usize).next_multiple_of(4)) as u32
+    }
+}
+
+impl ConfigStore {
+
+    // Find the active page, formatting the region if neither page holds a valid header
+    // start must be page aligned with two pages reserved, see storage::CONFIG_STORE_START
+    pub async fn mount(flash: &'static SharedFlash, start: u32) -> Result<Self, ConfigStoreError> {
+        let pages = [start, start + PAGE_SIZE];
+        let mut store = ConfigStore { flash, pages, active: 0, generation: 0, write_offset: PAGE_HEADER_LEN, torn: false };
+
+        let generations = [store.page_generation(0).await?, store.page_generation(1).await?];
+        match generations {
+            [Some(gen_0), Some(gen_1)] if gen_1 > gen_0 => store.set_active(1, gen_1),
+            [Some(gen_0), _] => store.set_active(0, gen_0),
+            [None, Some(gen_1)] => store.set_active(1, gen_1),
+            [None, None] => {
+                d_info!("Config store empty, formatting");
+                store.erase_page(0).await?;
+                store.write_page_header(0, 1).await?;
+                store.set_active(0, 1);
+            }
+        }
+
+        (store.write_offset, store.torn) = store.find_end().await?;
+        if store.torn {
+            warn!("Config store log is torn, the next write compacts to the other page");
+        }
+        d_info!("Config store on page {}, generation {}, {} bytes used", store.active, store.generation, store.write_offset);
+
+        Ok(store)
+    }
+
+    // Latest value for a key, returns the value length or None if the key was never written
+    pub async fn read(&mut self, key: u8, version: u8, buf: &mut [u8]) -> Result<Option<usize>, ConfigStoreError> {
+        let page = self.pages[self.active];
+        let mut found = None;
+        let mut offset = PAGE_HEADER_LEN;
+
+        while let Some(header) = self.read_record_header(page + offset).await? {
+            if header.key == key && header.version == version && self.record_valid(page + offset, &header).await? {
+                found = Some((offset, header.len as usize));
+            }
+            offset += RecordHeader::record_len(header.len);
+        }
+
+        match found {
+            Some((offset, len)) if len <= buf.len() => {
+                self.read_flash(page + offset + RECORD_HEADER_LEN as u32, &mut buf[..len]).await?;
+                Ok(Some(len))
+            }
+            Some(_) => Err(ConfigStoreError::TooLarge),
+            None => Ok(None),
+        }
+    }
+
+    // Append a new value for a key, compacting into the other page when the active one is full
+    pub async fn write(&mut self, key: u8, version: u8, value: &[u8]) -> Result<(), ConfigStoreError> {
+        if key == 0xFF {
+            return Err(ConfigStoreError::InvalidKey);
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(ConfigStoreError::TooLarge);
+        }
+
+        let record_len = RecordHeader::record_len(value.len() as u8);
+        if self.torn || self.write_offset + record_len > PAGE_SIZE {
+            return self.compact(key, version, value).await;
+        }
+
+        // A failed write may have programmed part of the record, don't append over it
+        let page = self.pages[self.active];
+        if let Err(e) = self.write_record(page + self.write_offset, key, version, value).await {
+            self.torn = true;
+            return Err(e);
+        }
+        self.write_offset += record_len;
+
+        Ok(())
+    }
+
+    // Copy the latest valid record of every other key to the other page and append the new value for key
+    async fn compact(&mut self, key: u8, version: u8, value: &[u8]) -> Result<(), ConfigStoreError> {
+        let src = self.pages[self.active];
+        let dst_index = 1 - self.active;
+        let dst = self.pages[dst_index];
+
+        // Offset and length of the latest valid record per key
+        let mut latest: Vec<(u8, u32, u32), MAX_KEYS> = Vec::new();
+        let mut offset = PAGE_HEADER_LEN;
+        while let Some(header) = self.read_record_header(src + offset).await? {
+            let record_len = RecordHeader::record_len(header.len);
+            if header.key != key && self.record_valid(src + offset, &header).await? {
+                match latest.iter_mut().find(|(latest_key, _, _)| *latest_key == header.key) {
+                    Some(entry) => *entry = (header.key, offset, record_len),
+                    None => latest.push((header.key, offset, record_len)).map_err(|_| ConfigStoreError::TooManyKeys)?,
+                }
+            }
+            offset += record_len;
+        }
+
+        // Check it all fits before the other page is erased
+        let used: u32 = latest.iter().map(|&(_, _, record_len)| record_len).sum();
+        if PAGE_HEADER_LEN + used + RecordHeader::record_len(value.len() as u8) > PAGE_SIZE {
+            return Err(ConfigStoreError::TooManyKeys);
+        }
+
+        self.erase_page(dst_index).await?;
+
+        let mut dst_offset = PAGE_HEADER_LEN;
+        let mut buf = [0u8; MAX_VALUE_LEN];
+        for &(_, offset, _) in latest.iter() {
+            if let Some(header) = self.read_record_header(src + offset).await? {
+                let buf = &mut buf[..header.len as usize];
+                self.read_flash(src + offset + RECORD_HEADER_LEN as u32, buf).await?;
+                self.write_record(dst + dst_offset, header.key, header.version, buf).await?;
+                dst_offset += RecordHeader::record_len(header.len);
+            }
+        }
+
+        // The pending value goes in before the header, so switching pages can't lose it
+        self.write_record(dst + dst_offset, key, version, value).await?;
+        dst_offset += RecordHeader::record_len(value.len() as u8);
+
+        // Header last - until it lands the old page stays active
+        let generation = self.generation.wrapping_add(1);
+        self.write_page_header(dst_index, generation).await?;
+        self.set_active(dst_index, generation);
+        self.write_offset = dst_offset;
+        self.torn = false;
+
+        d_info!("Config store compacted to page {}, generation {}", dst_index, generation);
+        Ok(())
+    }
+
+    fn set_active(&mut self, index: usize, generation: u32) {
+        self.active = index;
+        self.generation = generation;
+    }
+
+    async fn page_generation(&mut self, index: usize) -> Result<Option<u32>, ConfigStoreError> {
+        let mut header = [0u8; PAGE_HEADER_LEN as usize];
+        self.read_flash(self.pages[index], &mut header).await?;
+
+        let magic = u16::from_le_bytes([header[0], header[1]]);
+        let format = u16::from_le_bytes([header[2], header[3]]);
+        let generation = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+        if magic != MAGIC || format != FORMAT || generation == ERASED_WORD {
+            return Ok(None);
+        }
+        Ok(Some(generation))
+    }
+
+    async fn write_page_header(&mut self, index: usize, generation: u32) -> Result<(), ConfigStoreError> {
+        let mut header = [0u8; PAGE_HEADER_LEN as usize];
+        header[0..2].copy_from_slice(&MAGIC.to_le_bytes());
+        header[2..4].copy_from_slice(&FORMAT.to_le_bytes());
+        header[4..8].copy_from_slice(&generation.to_le_bytes());
+
+        self.flash.lock().await.write(self.pages[index], &header).await.map_err(|_| ConfigStoreError::Flash)
+    }
+
+    async fn erase_page(&mut self, index: usize) -> Result<(), ConfigStoreError> {
+        let page = self.pages[index];
+        self.flash.lock().await.erase(page, page + PAGE_SIZE).await.map_err(|_| ConfigStoreError::Flash)
+    }
+
+    // Offset after the last record, and whether the log stopped on a torn header instead of erased flash
+    async fn find_end(&mut self) -> Result<(u32, bool), ConfigStoreError> {
+        let page = self.pages[self.active];
+        let mut offset = PAGE_HEADER_LEN;
+        while let Some(header) = self.read_record_header(page + offset).await? {
+            offset += RecordHeader::record_len(header.len);
+        }
+
+        if offset + RECORD_HEADER_LEN as u32 > PAGE_SIZE {
+            return Ok((offset, false));
+        }
+        let mut word = [0u8; 4];
+        self.read_flash(page + offset, &mut word).await?;
+        Ok((offset, u32::from_le_bytes(word) != ERASED_WORD))
+    }
+
+    // None at the end of the log
+    async fn read_record_header(&mut self, addr: u32) -> Result<Option<RecordHeader>, ConfigStoreError> {
+        let page_end = addr - addr % PAGE_SIZE + PAGE_SIZE;
+        if addr + RECORD_HEADER_LEN as u32 > page_end {
+            return Ok(None);
+        }
+
+        let mut bytes = [0u8; RECORD_HEADER_LEN];
+        self.read_flash(addr, &mut bytes).await?;
+        if u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) == ERASED_WORD {
+            return Ok(None);
+        }
+
+        let header = RecordHeader::parse(&bytes);
+        if header.len as usize > MAX_VALUE_LEN || addr + RecordHeader::record_len(header.len) > page_end {
+            // Torn header - nothing after it can be trusted
+            warn!("Config store record at {:#x} is corrupt", addr);
+            return Ok(None);
+        }
+        Ok(Some(header))
+    }
+
+    async fn record_valid(&mut self, addr: u32, header: &RecordHeader) -> Result<bool, ConfigStoreError> {
+        let mut value = [0u8; MAX_VALUE_LEN];
+        let value = &mut value[..header.len as usize];
+        self.read_flash(addr + RECORD_HEADER_LEN as u32, value).await?;
+        Ok(RecordHeader::crc(header.key, header.version, value) == header.crc)
+    }
+
+    async fn write_record(&mut self, addr: u32, key: u8, version: u8, value: &[u8]) -> Result<(), ConfigStoreError> {
+        let mut record = [0xFFu8; RECORD_HEADER_LEN + MAX_VALUE_LEN];
+        record[0] = key;
+        record[1] = version;
+        record[2] = value.len() as u8;
+        record[4..8].copy_from_slice(&RecordHeader::crc(key, version, value).to_le_bytes());
+        record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + value.len()].copy_from_slice(value);
+
+        let record_len = RecordHeader::record_len(value.len() as u8) as usize;
+        self.flash.lock().await.write(addr, &record[..record_len]).await.map_err(|_| ConfigStoreError::Flash)
+    }
+
+    async fn read_flash(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), ConfigStoreError> {
+        self.flash.lock().await.read(addr, buf).await.map_err(|_| ConfigStoreError::Flash)
+    }
+}
+
+// RuntimeConfig encoding, bump when the layout changes - old records are then ignored
+const RUNTIME_CONFIG_VERSION: u8 = 1;
+const RUNTIME_CONFIG_LEN: usize = 11;
+
+fn encode_runtime_config(runtime: &RuntimeConfig) -> [u8; RUNTIME_CONFIG_LEN] {
+    let mut buf = [0u8; RUNTIME_CONFIG_LEN];
+    buf[0..4].copy_from_slice(&runtime.sample_ms.to_le_bytes());
+    buf[4..8].copy_from_slice(&runtime.notify_ms.to_le_bytes());
+    buf[8..11].copy_from_slice(&runtime.oversampling);
+    buf
+}
+
+fn decode_runtime_config(buf: &[u8; RUNTIME_CONFIG_LEN]) -> RuntimeConfig {
+    RuntimeConfig {
+        sample_ms: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+        notify_ms: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        oversampling: [buf[8], buf[9], buf[10]],
+    }
+}
+
+// Publish the stored runtime config, call before the sensor tasks start
+pub async fn restore_runtime_config(store: &mut ConfigStore) -> Result<(), ConfigStoreError> {
+    let mut buf = [0u8; RUNTIME_CONFIG_LEN];
+    match store.read(KEY_RUNTIME_CONFIG, RUNTIME_CONFIG_VERSION, &mut buf).await? {
+        Some(RUNTIME_CONFIG_LEN) => {
+            let runtime = decode_runtime_config(&buf);
+            match config::set(runtime) {
+                Ok(()) => d_info!("Restored runtime config: {}", runtime),
+                Err(e) => warn!("Stored runtime config rejected: {:?}", e),
+            }
+        }
+        _ => d_info!("No stored runtime config, using defaults"),
+    }
+    Ok(())
+}
+
+const BOOT_COUNT_VERSION: u8 = 1;
+
+// Count this boot and return its number, stamped on history records next to uptime
+// The first boot is 1, 0 stays free for records whose boot is unknown
+pub async fn next_boot(store: &mut ConfigStore) -> Result<u16, ConfigStoreError> {
+    let mut buf = [0u8; 2];
+    let last = match store.read(KEY_BOOT_COUNT, BOOT_COUNT_VERSION, &mut buf).await? {
+        Some(2) => u16::from_le_bytes(buf),
+        _ => 0,
+    };
+    let boot = last.wrapping_add(1).max(1);
+    store.write(KEY_BOOT_COUNT, BOOT_COUNT_VERSION, &boot.to_le_bytes()).await?;
+    d_info!("Boot {}", boot);
+    Ok(boot)
+}
+
+// Persist every runtime config change
+#[embassy_executor::task]
+pub async fn config_persist(mut store: ConfigStore) {
+    let mut config_rx = CONFIG_WATCH.receiver().unwrap();
+    let mut saved = config_rx.try_changed().unwrap_or_else(config::current);
+    loop {
+        let runtime = config_rx.changed().await;
+        if runtime == saved {
+            continue;
+        }
+
+        match store.write(KEY_RUNTIME_CONFIG, RUNTIME_CONFIG_VERSION, &encode_runtime_config(&runtime)).await {
+            Ok(()) => {
+                d_info!("Saved runtime config");
+                saved = runtime;
+            }
+            Err(e) => warn!("Saving runtime config failed: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Health counters for the diagnostics service
+// Sensor tasks and the BLE layer bump these, ble_services copies them into the GATT table
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+use embassy_time::Instant;
+
+use crate::embassy_hal::pac;
+use crate::system::sensor_error::SensorUpdateError;
+
+pub static SENSOR_READS: AtomicU32 = AtomicU32::new(0);     // Successful BME680 conversions
+pub static I2C_ERRORS: AtomicU32 = AtomicU32::new(0);       // Bus errors and missing devices, every sensor
+pub static LAST_ERROR: AtomicU8 = AtomicU8::new(0);         // SensorUpdateError::code, kept after recovery
+pub static SENSOR_REINITS: AtomicU32 = AtomicU32::new(0);   // BME680 / TSL2591 re-initializations after repeated failures
+
+pub static CONNECTIONS: AtomicU32 = AtomicU32::new(0);
+pub static NOTIFY_DROPPED: AtomicU32 = AtomicU32::new(0);   // Notifications lost to full TX buffers
+
+static RESET_REASON: AtomicU32 = AtomicU32::new(0);         // POWER.RESETREAS at boot
+
+const STACK_PAINT: u32 = 0xDEAD_BEEF;
+const STACK_PAINT_MARGIN: usize = 256;                      // Left alone below the caller's frame
+
+// cortex-m-rt: end of .bss/.uninit, the lowest address the stack can grow to
+unsafe extern "C" {
+    static __sheap: u32;
+}
+
+pub fn record_bme_error(error: SensorUpdateError) {
+    if matches!(error, SensorUpdateError::Bus | SensorUpdateError::NotFound) {
+        I2C_ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+    LAST_ERROR.store(SensorUpdateError::code(Some(error)), Ordering::Relaxed);
+}
+
+pub fn uptime_s() -> u32 {
+    Instant::now().as_secs() as u32
+}
+
+// Latch and clear the reset reason - POWER belongs to the SoftDevice once it is enabled,
+// so this has to run before BLE starts (start_peripherals does it)
+pub fn capture_reset_reason() {
+    let reason = pac::POWER.resetreas().read();
+    pac::POWER.resetreas().write_value(reason);     // Bits are cleared by writing 1
+    RESET_REASON.store(reason.0, Ordering::Relaxed);
+}
+
+// RESETREAS bits - 0 means power-on reset
+pub fn reset_reason() -> u32 {
+    RESET_REASON.load(Ordering::Relaxed)
+}
+
+// Fill the unused stack with a known pattern so free_stack() can find the high-water mark
+// Call once, as early as possible
+pub fn paint_stack() {
+    let bottom = &raw const __sheap as usize;
+    let top = cortex_m::register::msp::read() as usize - STACK_PAINT_MARGIN;
+
+    let mut addr = bottom;
+    while addr < top {
+        // Nothing lives between the end of RAM data and the current frame yet
+        unsafe { core::ptr::write_volatile(addr as *mut u32, STACK_PAINT) };
+        addr += 4;
+    }
+}
+
+// Bytes of stack never touched since paint_stack()
+pub fn free_stack() -> u32 {
+    let bottom = &raw const __sheap as usize;
+    let top = cortex_m::register::msp::read() as usize;
+
+    let mut addr = bottom;
+    while addr < top && unsafe { core::ptr::read_volatile(addr as *const u32) } == STACK_PAINT {
+        addr += 4;
+    }
+    (addr - bottom) as u32
+}
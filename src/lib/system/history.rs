@@ -0,0 +1,224 @@
+// Sensor history ring buffer on reserved flash pages
+//
+// Fixed 24 byte slots: 20 byte record + crc32. The record with sequence number seq always lives in slot
+// seq % slot count, so lookups need no index. A page is erased right before its first slot is written,
+// which drops the oldest page of records once the ring has wrapped.
+// Each record carries the boot number (config_store::next_boot) and the uptime seconds within that boot.
+// Uptime restarts at every boot, so clients order records by seq and only compare uptime_s between records with
+// the same boot number. Records stored before the boot counter could be read have boot 0.
+use static_cell::StaticCell;
+
+use embassy_sync::mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_time::{Duration, Timer};
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::system::config;
+use crate::system::state::{self, Channel, SensorSnapshot};
+use crate::system::storage::{PAGE_SIZE, SharedFlash, crc32};
+use crate::{d_info, warn};
+
+pub const RECORD_LEN: usize = 20;
+const RECORD_FORMAT: u8 = 2;        // Part of the CRC, records in an older layout read as corrupt
+const SLOT_LEN: u32 = RECORD_LEN as u32 + 4;
+const SLOTS_PER_PAGE: u32 = PAGE_SIZE / SLOT_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum HistoryError {
+    Flash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct HistoryRecord {
+    pub seq: u32,
+    pub boot: u16,
+    pub uptime_s: u32,          // Since the start of boot
+    pub temperature: i16,       // 0.01 degC
+    pub pressure: u32,          // Pa
+    pub humidity: u16,          // 0.01 %RH
+    pub iaq: u16,               // 0 - 500
+}
+
+impl HistoryRecord {
+    // Little-endian, same layout as the history service records characteristic
+    pub fn to_bytes(&self) -> [u8; RECORD_LEN] {
+        let mut buf = [0u8; RECORD_LEN];
+        buf[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.boot.to_le_bytes());
+        buf[6..10].copy_from_slice(&self.uptime_s.to_le_bytes());
+        buf[10..12].copy_from_slice(&self.temperature.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.pressure.to_le_bytes());
+        buf[16..18].copy_from_slice(&self.humidity.to_le_bytes());
+        buf[18..20].copy_from_slice(&self.iaq.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; RECORD_LEN]) -> Self {
+        HistoryRecord {
+            seq: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            boot: u16::from_le_bytes([buf[4], buf[5]]),
+            uptime_s: u32::from_le_bytes([buf[6], buf[7], buf[8], buf[9]]),
+            temperature: i16::from_le_bytes([buf[10], buf[11]]),
+            pressure: u32::from_le_bytes([buf[12], buf[13], buf[14], buf[15]]),
+            humidity: u16::from_le_bytes([buf[16], buf[17]]),
+            iaq: u16::from_le_bytes([buf[18], buf[19]]),
+        }
+    }
+
+    // Values from a snapshot, seq and boot are filled in by HistoryLog::append
+    pub fn from_snapshot(snapshot: &SensorSnapshot) -> Self {
+        HistoryRecord {
+            seq: 0,
+            boot: 0,
+            uptime_s: snapshot.captured.as_secs() as u32,
+            temperature: snapshot.temperature.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+            pressure: snapshot.pressure,
+            humidity: (snapshot.humidity / 10).min(u16::MAX as u32) as u16,
+            iaq: snapshot.iaq,
+        }
+    }
+}
+
+pub struct HistoryLog {
+    flash: &'static SharedFlash,
+    start: u32,
+    slots: u32,
+    boot: u16,
+    oldest_seq: u32,
+    next_seq: u32,
+}
+
+pub type SharedHistory = Mutex<ThreadModeRawMutex, HistoryLog>;
+
+static HISTORY: StaticCell<SharedHistory> = StaticCell::new();
+
+// Share the log between the logging task and the history service
+pub fn init_history(log: HistoryLog) -> &'static SharedHistory {
+    HISTORY.init(Mutex::new(log))
+}
+
+impl HistoryLog {
+
+    // Scan every slot for the newest and oldest valid record
+    // start must be page aligned, see storage::HISTORY_START
+    // boot is stamped on every record appended from now on, see config_store::next_boot
+    pub async fn mount(flash: &'static SharedFlash, start: u32, pages: u32, boot: u16) -> Result<Self, HistoryError> {
+        let mut log = HistoryLog { flash, start, slots: pages * SLOTS_PER_PAGE, boot, oldest_seq: 0, next_seq: 0 };
+
+        let mut range: Option<(u32, u32)> = None;
+        for slot in 0..log.slots {
+            if let Some(record) = log.read_slot(slot).await? {
+                if record.seq % log.slots != slot {
+                    continue;
+                }
+                range = Some(match range {
+                    Some((oldest, newest)) => (oldest.min(record.seq), newest.max(record.seq)),
+                    None => (record.seq, record.seq),
+                });
+            }
+        }
+
+        if let Some((oldest, newest)) = range {
+            log.oldest_seq = oldest;
+            log.next_seq = newest.wrapping_add(1);
+        }
+
+        // A torn write leaves a dirty slot behind - skip to the next clean slot or page
+        while log.next_seq % SLOTS_PER_PAGE != 0 && !log.slot_erased(log.next_seq % log.slots).await? {
+            log.next_seq = log.next_seq.wrapping_add(1);
+        }
+
+        d_info!("History: records {} to {}", log.oldest_seq, log.next_seq);
+        Ok(log)
+    }
+
+    // Oldest stored sequence number and the one the next record will get
+    pub fn range(&self) -> (u32, u32) {
+        (self.oldest_seq, self.next_seq)
+    }
+
+    pub async fn append(&mut self, mut record: HistoryRecord) -> Result<u32, HistoryError> {
+        let seq = self.next_seq;
+        let slot = seq % self.slots;
+        record.seq = seq;
+        record.boot = self.boot;
+
+        // Entering a page - drop the records it held
+        if slot % SLOTS_PER_PAGE == 0 {
+            let page = self.start + (slot / SLOTS_PER_PAGE) * PAGE_SIZE;
+            self.flash.lock().await.erase(page, page + PAGE_SIZE).await.map_err(|_| HistoryError::Flash)?;
+
+            let first_kept = (seq + SLOTS_PER_PAGE).saturating_sub(self.slots);
+            self.oldest_seq = self.oldest_seq.max(first_kept);
+        }
+
+        let bytes = record.to_bytes();
+        let mut slot_buf = [0u8; SLOT_LEN as usize];
+        slot_buf[..RECORD_LEN].copy_from_slice(&bytes);
+        slot_buf[RECORD_LEN..].copy_from_slice(&record_crc(&bytes).to_le_bytes());
+        self.flash.lock().await.write(self.slot_addr(slot), &slot_buf).await.map_err(|_| HistoryError::Flash)?;
+
+        self.next_seq = seq.wrapping_add(1);
+        Ok(seq)
+    }
+
+    // None if the record was overwritten, never written or is corrupt
+    pub async fn read(&mut self, seq: u32) -> Result<Option<HistoryRecord>, HistoryError> {
+        if seq < self.oldest_seq || seq >= self.next_seq {
+            return Ok(None);
+        }
+        let record = self.read_slot(seq % self.slots).await?;
+        Ok(record.filter(|record| record.seq == seq))
+    }
+
+    fn slot_addr(&self, slot: u32) -> u32 {
+        self.start + (slot / SLOTS_PER_PAGE) * PAGE_SIZE + (slot % SLOTS_PER_PAGE) * SLOT_LEN
+    }
+
+    async fn read_slot(&mut self, slot: u32) -> Result<Option<HistoryRecord>, HistoryError> {
+        let mut slot_buf = [0u8; SLOT_LEN as usize];
+        self.flash.lock().await.read(self.slot_addr(slot), &mut slot_buf).await.map_err(|_| HistoryError::Flash)?;
+
+        let mut bytes = [0u8; RECORD_LEN];
+        bytes.copy_from_slice(&slot_buf[..RECORD_LEN]);
+        let crc = u32::from_le_bytes([slot_buf[20], slot_buf[21], slot_buf[22], slot_buf[23]]);
+        if record_crc(&bytes) != crc {
+            return Ok(None);
+        }
+        Ok(Some(HistoryRecord::from_bytes(&bytes)))
+    }
+
+    async fn slot_erased(&mut self, slot: u32) -> Result<bool, HistoryError> {
+        let mut slot_buf = [0u8; SLOT_LEN as usize];
+        self.flash.lock().await.read(self.slot_addr(slot), &mut slot_buf).await.map_err(|_| HistoryError::Flash)?;
+        Ok(slot_buf.iter().all(|&b| b == 0xFF))
+    }
+}
+
+fn record_crc(bytes: &[u8; RECORD_LEN]) -> u32 {
+    let mut buf = [0u8; 1 + RECORD_LEN];
+    buf[0] = RECORD_FORMAT;
+    buf[1..].copy_from_slice(bytes);
+    crc32(&buf)
+}
+
+// Log the current sensor values every period_ms, whether or not a client is connected
+// Periods without a recent BME680 sample (within 3 sample periods) are skipped rather than logging stale values
+#[embassy_executor::task]
+pub async fn history_log(history: &'static SharedHistory, period_ms: u64) {
+    loop {
+        Timer::after_millis(period_ms).await;
+
+        let snapshot = state::snapshot();
+        let max_age = Duration::from_millis(config::current().sample_ms as u64 * 3);
+        if !snapshot.is_fresh(Channel::Temperature, max_age) {
+            warn!("History record skipped: no fresh sample");
+            continue;
+        }
+
+        match history.lock().await.append(HistoryRecord::from_snapshot(&snapshot)).await {
+            Ok(seq) => d_info!("History record {} stored", seq),
+            Err(e) => warn!("History record not stored: {:?}", e),
+        }
+    }
+}
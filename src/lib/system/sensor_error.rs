@@ -0,0 +1,29 @@
+// Sensor task failures as published in the snapshot and the diagnostics service
+// Kept free of driver imports so state and diagnostics don't pull in the sensor tasks
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SensorUpdateError {
+    NotFound,
+    Bus,
+    ChipIdMismatch(u8),     // ID read back from the chip
+    CalibrationInvalid,
+    Timeout,
+    InvalidConfig,
+    Saturated,              // TSL2591 reading out of range at every gain / integration time
+}
+
+impl SensorUpdateError {
+    // Status byte for BLE clients, 0 means no error
+    pub fn code(error: Option<SensorUpdateError>) -> u8 {
+        match error {
+            None => 0,
+            Some(SensorUpdateError::NotFound) => 1,
+            Some(SensorUpdateError::Bus) => 2,
+            Some(SensorUpdateError::ChipIdMismatch(_)) => 3,
+            Some(SensorUpdateError::CalibrationInvalid) => 4,
+            Some(SensorUpdateError::Timeout) => 5,
+            Some(SensorUpdateError::InvalidConfig) => 6,
+            Some(SensorUpdateError::Saturated) => 7,
+        }
+    }
+}
@@ -1,32 +1,69 @@
-/// Setup I2C and periodically update sensor atomics
+/// Setup I2C and periodically publish sensor snapshots
 use core::sync::atomic::Ordering;
 use static_cell::StaticCell;
+use heapless::Vec;
 
 use embassy_time::Timer;
+use embassy_futures::select::{select, Either};
 use embassy_sync::mutex::Mutex;
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
 
 use embassy_hal_internal::Peri;
 
-use crate::embassy_hal::gpio::Pin;
-use crate::embassy_hal::{self, Peripherals, bind_interrupts, interrupt::Priority, twim::{self, Twim}};
+use crate::embassy_hal::gpio::{AnyPin, Input, Pin, Pull};
+use crate::embassy_hal::{self, Peripherals, bind_interrupts, interrupt::{self, InterruptExt, Priority}, twim::{self, Twim}};
+use crate::embassy_hal::saadc::{self, ChannelConfig, Saadc};
 use crate::embassy_hal::peripherals;
 use crate::d_peripherals::chip_implementations::I2CMutexWrapper;
 use crate::d_peripherals::sensors::bme680::BME680;
+use crate::system::bme680_ext::{BME680Ext, BME680ExtError, Bme680Config, HeaterProfile, CHIP_ID, CHIP_ID_REG};
+use crate::system::iaq::IaqEstimator;
+use crate::system::tsl2591_driver::{Persist, TSL2591Driver, TSL2591Error};
+use crate::system::battery::{BatteryConfig, BatteryFilter, saadc_to_mv};
+use crate::system::config::{self, RuntimeConfig, CONFIG_WATCH};
+use crate::system::diagnostics;
+use crate::system::sensor_error::SensorUpdateError;
 
-use crate::system::state::{TEMP_VAL, PRESSURE_VAL};
-use crate::{d_log::dlogger::DLogger, d_info};
+use crate::system::state::{Channel, publish, publish_error, publish_tsl_error};
+use crate::{d_log::dlogger::DLogger, d_info, warn};
 
-bind_interrupts!(struct Irqs {TWISPI0 => twim::InterruptHandler<peripherals::TWISPI0>;});
+bind_interrupts!(struct Irqs {
+    TWISPI0 => twim::InterruptHandler<peripherals::TWISPI0>;
+    SAADC => saadc::InterruptHandler;
+});
 static I2C_MUTEX: StaticCell<Mutex<ThreadModeRawMutex, Twim<'static>>> = StaticCell::new();
 static TX_BUF: StaticCell<[u8; 32]> = StaticCell::new();
 
-#[derive(Debug)]
-pub enum SensorUpdateError {
-    NotFound,
-    // PeripheralStartError,
-    // I2CStartError,
-    // BME680Error(BME680Error),
+// Gas readings averaged for the IAQ baseline (~3.5 minutes at the default 500 ms sample period,
+// each forced conversion with the heater adds ~180 ms)
+const IAQ_BURN_IN_SAMPLES: u32 = 300;
+
+// Consecutive failed samples before the sensor is probed and initialized again
+const BME_MAX_FAILURES: u32 = 5;
+const TSL_MAX_FAILURES: u32 = 5;
+const BACKOFF_MIN_MS: u64 = 100;
+const BACKOFF_MAX_MS: u64 = 30_000;
+
+impl From<BME680ExtError> for SensorUpdateError {
+    fn from(e: BME680ExtError) -> Self {
+        match e {
+            BME680ExtError::Bus => SensorUpdateError::Bus,
+            BME680ExtError::Timeout => SensorUpdateError::Timeout,
+            BME680ExtError::InvalidProfile | BME680ExtError::InvalidConfig => SensorUpdateError::InvalidConfig,
+        }
+    }
+}
+
+impl From<TSL2591Error> for SensorUpdateError {
+    fn from(e: TSL2591Error) -> Self {
+        match e {
+            TSL2591Error::Bus => SensorUpdateError::Bus,
+            TSL2591Error::NotFound => SensorUpdateError::NotFound,
+            TSL2591Error::Saturated => SensorUpdateError::Saturated,
+            TSL2591Error::Timeout => SensorUpdateError::Timeout,
+            TSL2591Error::NoInterruptPin => SensorUpdateError::InvalidConfig,
+        }
+    }
 }
 
 
@@ -34,6 +71,9 @@ pub enum SensorUpdateError {
 // Very finicky - HAL interrupts have to be given lower priority than softdeivce
 // this block needs to come before SoftDevice is enabled
 pub fn start_peripherals() -> Peripherals {
+    diagnostics::paint_stack();
+    diagnostics::capture_reset_reason();
+
     let mut ecfg = embassy_hal::config::Config::default();
     ecfg.gpiote_interrupt_priority = Priority::P2;
     ecfg.time_interrupt_priority = Priority::P2; // for time-driver-rtc1
@@ -61,32 +101,348 @@ where
     i2c_mutex_wrapper
 }
 
+// Initialize SAADC with a single battery channel
+// e.g. ChannelConfig::single_ended(VddInput) or ChannelConfig::single_ended(VddhDiv5Input)
+pub fn start_saadc(saadc: Peri<'static, peripherals::SAADC>, channel: ChannelConfig<'static>) -> Saadc<'static, 1> {
+    // Same as the other HAL interrupts - keep it below the softdevice
+    interrupt::SAADC.set_priority(Priority::P2);
+
+    let config = saadc::Config::default();
+    Saadc::new(saadc, Irqs, config, [channel])
+}
+
 // Async bme680 reads
+// Sample period and oversampling follow system::config, changes apply on the next sample
+// Failed samples are retried with exponential backoff, after BME_MAX_FAILURES in a row the sensor is
+// probed and initialized again. The current error is published in the snapshot.
 #[embassy_executor::task]
-pub async fn bme_update(i2c_bus: I2CMutexWrapper, delay_ms: u64) {
+pub async fn bme_update(i2c_bus: I2CMutexWrapper) {
+    let bus = i2c_bus.0;
+    let mut config_rx = CONFIG_WATCH.receiver().unwrap();
+    let mut runtime = config_rx.try_changed().unwrap_or_else(config::current);
 
-    // Do some simple chip reads
-    d_info!("Setting up BME680");
+    // Gas baseline survives re-initialization
+    let mut iaq = IaqEstimator::new(IAQ_BURN_IN_SAMPLES);
+    let mut backoff = Backoff::new(BACKOFF_MIN_MS, BACKOFF_MAX_MS);
 
-    let mut bme = BME680::new(i2c_bus, 0x76).await.unwrap();
-    bme.config(1).await.unwrap();
     loop {
+        d_info!("Setting up BME680");
+        let mut bme = match bme_init(bus, &runtime).await {
+            Ok(bme) => bme,
+            Err(e) => {
+                warn!("BME680 init failed: {:?}", e);
+                diagnostics::record_bme_error(e);
+                publish_error(Some(e));
+                Timer::after_millis(backoff.next_ms()).await;
+                continue;
+            }
+        };
+        backoff.reset();
 
-        // Read register with generic register read
-        bme.chip.read_field_str("chip_id").await.unwrap();
-        bme.chip.read_reg(0xD0).await.unwrap();
+        let mut failures = 0;
+        while failures < BME_MAX_FAILURES {
+            let delay_ms = match bme_sample(&mut bme, &mut iaq).await {
+                Ok(()) => {
+                    diagnostics::SENSOR_READS.fetch_add(1, Ordering::Relaxed);
+                    failures = 0;
+                    backoff.reset();
+                    publish_error(None);
+                    runtime.sample_ms as u64
+                }
+                Err(e) => {
+                    failures += 1;
+                    warn!("BME680 sample failed ({}/{}): {:?}", failures, BME_MAX_FAILURES, e);
+                    diagnostics::record_bme_error(e);
+                    publish_error(Some(e));
+                    backoff.next_ms()
+                }
+            };
 
-        let temp_val = bme.read_temperature().await.unwrap();
-        let pressure_val = bme.read_pressure().await.unwrap();
+            DLogger::d_sep();
 
-        DLogger::d_sep();
+            // Wait before next scan - a config write cuts the wait short
+            if let Either::Second(new_runtime) = select(Timer::after_millis(delay_ms), config_rx.changed()).await {
+                d_info!("BME680 runtime config: {}", new_runtime);
+                match new_runtime.apply_to(bme.config()) {
+                    Ok(new_config) => {
+                        if let Err(e) = bme.apply_config(&new_config).await {
+                            warn!("BME680 config not applied: {:?}", e);
+                        }
+                    }
+                    Err(e) => warn!("BME680 config rejected: {:?}", e),
+                }
+                runtime = new_runtime;
+            }
+        }
+
+        warn!("BME680 unresponsive, re-initializing");
+        diagnostics::SENSOR_REINITS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Probe, check the chip and program the heater - the sensor is ready for forced conversions afterwards
+async fn bme_init(bus: &'static Mutex<ThreadModeRawMutex, Twim<'static>>, runtime: &RuntimeConfig) -> Result<BME680Ext, SensorUpdateError> {
+    let bme = BME680::new(I2CMutexWrapper(bus), 0x76).await.map_err(|_| SensorUpdateError::NotFound)?;
+
+    let chip_id = bme.chip.read_reg(CHIP_ID_REG).await.map_err(|_| SensorUpdateError::Bus)?;
+    if chip_id != CHIP_ID {
+        return Err(SensorUpdateError::ChipIdMismatch(chip_id));
+    }
+
+    let mut bme = BME680Ext::new(bme).await?;
+    if !bme.calib.is_valid() {
+        return Err(SensorUpdateError::CalibrationInvalid);
+    }
+
+    // First conversion without gas gives the ambient temperature for heater compensation
+    bme.apply_config(&Bme680Config::default()).await?;
+    bme.measure().await?;
+
+    // Single heater profile - 320 degC for 150 ms
+    bme.set_heater_profile(0, HeaterProfile { target_temp_c: 320, duration_ms: 150 }, None).await?;
+    let config = Bme680Config::builder().heater_profile(0).run_gas(true).build()?;
+    bme.apply_config(&runtime.apply_to(&config).unwrap_or(config)).await?;
+
+    Ok(bme)
+}
+
+// One forced conversion, published to the snapshot
+async fn bme_sample(bme: &mut BME680Ext, iaq: &mut IaqEstimator) -> Result<(), SensorUpdateError> {
+    let sample = bme.measure().await?;
+
+    // Heater takes a few cycles to stabilize - keep the last good value until then
+    // IAQ is humidity compensated, so it needs the humidity channel as well
+    match (sample.gas, sample.humidity) {
+        (Some(gas_val), Some(humidity_val)) => {
+            let iaq_val = iaq.update(gas_val, humidity_val);
+            d_info!("IAQ: {}", iaq_val);
+            // Same columns as the primer_logic/tests/traces files, so a bench log can be checked in as a trace
+            d_info!("IAQ trace: {},{}", gas_val, humidity_val);
+
+            publish(&[Channel::Gas, Channel::Iaq], |s| {
+                s.gas = gas_val;
+                s.iaq = iaq_val.iaq;
+                s.iaq_accuracy = iaq_val.accuracy as u8;
+            });
+        }
+        (Some(gas_val), None) => publish(&[Channel::Gas], |s| s.gas = gas_val),
+        (None, _) => {}
+    }
+
+    // Send data to channel - skipped channels are left unmarked
+    let mut channels: Vec<Channel, 3> = Vec::new();
+    let _ = channels.push(Channel::Temperature);
+    if sample.pressure.is_some() {
+        let _ = channels.push(Channel::Pressure);
+    }
+    if sample.humidity.is_some() {
+        let _ = channels.push(Channel::Humidity);
+    }
+    publish(&channels, |s| {
+        s.temperature = sample.temperature;
+        s.pressure = sample.pressure.unwrap_or(s.pressure);
+        s.humidity = sample.humidity.unwrap_or(s.humidity);
+    });
+
+    Ok(())
+}
+
+// Doubling retry delay, capped at max_ms
+struct Backoff {
+    min_ms: u64,
+    max_ms: u64,
+    next_ms: u64,
+}
+
+impl Backoff {
+    const fn new(min_ms: u64, max_ms: u64) -> Self {
+        Backoff { min_ms, max_ms, next_ms: min_ms }
+    }
+
+    fn next_ms(&mut self) -> u64 {
+        let delay_ms = self.next_ms;
+        self.next_ms = (self.next_ms * 2).min(self.max_ms);
+        delay_ms
+    }
+
+    fn reset(&mut self) {
+        self.next_ms = self.min_ms;
+    }
+}
+
+// Async tsl2591 reads
+// Same recovery as bme_update - failed reads are retried with exponential backoff, after TSL_MAX_FAILURES in
+// a row the sensor is probed and enabled again. The current error is published in the snapshot.
+#[embassy_executor::task]
+pub async fn tsl_update(i2c_bus: I2CMutexWrapper, delay_ms: u64) {
+    let bus = i2c_bus.0;
+    let mut backoff = Backoff::new(BACKOFF_MIN_MS, BACKOFF_MAX_MS);
+
+    loop {
+        d_info!("Setting up TSL2591");
+        let mut tsl = match tsl_init(bus).await {
+            Ok(tsl) => tsl,
+            Err(e) => {
+                tsl_failed("TSL2591 init failed", e);
+                Timer::after_millis(backoff.next_ms()).await;
+                continue;
+            }
+        };
+        backoff.reset();
+
+        let mut failures = 0;
+        while failures < TSL_MAX_FAILURES {
+            // Gain / integration time follow the light level
+            let wait_ms = match tsl.read_lux_auto().await {
+                Ok(lux_val) => {
+                    d_info!("TSL2591 lux: {}", lux_val);
+                    publish(&[Channel::Lux], |s| s.lux = (lux_val * 100.0) as u32);
+                    failures = 0;
+                    backoff.reset();
+                    publish_tsl_error(None);
+                    delay_ms
+                }
+                // Too bright even at the lowest gain - the sensor is fine, keep the normal period
+                Err(TSL2591Error::Saturated) => {
+                    tsl_failed("TSL2591 reading skipped", TSL2591Error::Saturated);
+                    delay_ms
+                }
+                Err(e) => {
+                    failures += 1;
+                    warn!("TSL2591 read failed ({}/{})", failures, TSL_MAX_FAILURES);
+                    tsl_failed("TSL2591 reading skipped", e);
+                    backoff.next_ms()
+                }
+            };
+
+            DLogger::d_sep();
+
+            // Wait before next scan
+            Timer::after_millis(wait_ms).await;
+        }
+
+        warn!("TSL2591 unresponsive, re-initializing");
+        diagnostics::SENSOR_REINITS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Probe and power up the tsl2591 for continuous reads
+async fn tsl_init(bus: &'static Mutex<ThreadModeRawMutex, Twim<'static>>) -> Result<TSL2591Driver, TSL2591Error> {
+    let mut tsl = TSL2591Driver::new(I2CMutexWrapper(bus)).await?;
+    tsl.enable().await?;
+    Ok(tsl)
+}
+
+// Event driven tsl2591 reads - the task only wakes when light leaves a window around the last reading
+// band_pct - window half-width as a percentage of the last full-spectrum count
+// Any failure sets the sensor up again after a backoff delay
+#[embassy_executor::task]
+pub async fn tsl_threshold_update(i2c_bus: I2CMutexWrapper, int_pin: Peri<'static, AnyPin>, band_pct: u16) {
+    let bus = i2c_bus.0;
+    let mut int_pin = Some(Input::new(int_pin, Pull::Up));
+    let mut backoff = Backoff::new(BACKOFF_MIN_MS, BACKOFF_MAX_MS);
+
+    loop {
+        d_info!("Setting up TSL2591 threshold interrupt");
+        let mut tsl = match TSL2591Driver::new(I2CMutexWrapper(bus)).await {
+            Ok(tsl) => tsl,
+            Err(e) => {
+                tsl_failed("TSL2591 init failed", e);
+                Timer::after_millis(backoff.next_ms()).await;
+                continue;
+            }
+        };
+        if let Some(pin) = int_pin.take() {
+            tsl.attach_interrupt(pin);
+        }
+
+        let e = tsl_threshold_loop(&mut tsl, band_pct, &mut backoff).await;
+        tsl_failed("TSL2591 threshold loop stopped", e);
+
+        // Keep the pin for the next driver instance
+        int_pin = tsl.detach_interrupt();
+        Timer::after_millis(backoff.next_ms()).await;
+    }
+}
+
+// Only returns on a failure
+async fn tsl_threshold_loop(tsl: &mut TSL2591Driver, band_pct: u16, backoff: &mut Backoff) -> TSL2591Error {
+    if let Err(e) = tsl.enable().await {
+        return e;
+    }
+
+    // Gain is fixed once interrupts are armed, pick it from the current light level
+    let mut lux_val = match tsl.read_lux_auto().await {
+        Ok(lux_val) => lux_val,
+        Err(TSL2591Error::Saturated) => 0.0,
+        Err(e) => return e,
+    };
+    let mut channels = match tsl.read_channels().await {
+        Ok(channels) => channels,
+        Err(e) => return e,
+    };
+    backoff.reset();
+    publish_tsl_error(None);
+
+    loop {
+        publish(&[Channel::Lux], |s| s.lux = (lux_val * 100.0) as u32);
+
+        // Re-arm around the last reading
+        let band = (channels.full as u32 * band_pct as u32 / 100).max(1) as u16;
+        let low = channels.full.saturating_sub(band);
+        let high = channels.full.saturating_add(band);
+        if let Err(e) = tsl.set_thresholds(low, high, Persist::Cycles3).await {
+            return e;
+        }
+        if let Err(e) = tsl.enable_interrupts(true, false).await {
+            return e;
+        }
 
-        // Send data to channel
-        TEMP_VAL.store(temp_val, Ordering::Relaxed);
-        PRESSURE_VAL.store(pressure_val, Ordering::Relaxed);
+        match tsl.wait_for_threshold().await {
+            Ok(event) => {
+                channels = event.channels;
+                lux_val = tsl.lux(channels);
+                d_info!("TSL2591 lux: {}", lux_val);
+            }
+            Err(e) => return e,
+        }
 
-        // Wait before next scan
-        Timer::after_millis(delay_ms).await;
+        DLogger::d_sep();
     }
+}
 
+// Log, count and publish a TSL2591 failure
+fn tsl_failed(what: &str, e: TSL2591Error) {
+    if matches!(e, TSL2591Error::Bus | TSL2591Error::NotFound) {
+        diagnostics::I2C_ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+    warn!("{}: {:?}", what, e);
+    publish_tsl_error(Some(e.into()));
 }
+
+// Async battery reads
+#[embassy_executor::task]
+pub async fn battery_update(mut saadc: Saadc<'static, 1>, config: BatteryConfig) {
+
+    d_info!("Setting up battery monitor");
+
+    saadc.calibrate().await;
+    let mut filter = BatteryFilter::new(config.smoothing_shift);
+    loop {
+
+        let mut buf = [0i16; 1];
+        saadc.sample(&mut buf).await;
+
+        let mv = filter.update(saadc_to_mv(buf[0], &config));
+        let level = config.curve.percent(mv);
+        d_info!("Battery: {} mV, {}%", mv, level);
+
+        publish(&[Channel::Battery], |s| {
+            s.battery_mv = mv;
+            s.battery_level = level;
+        });
+
+        // Wait before next sample
+        Timer::after_millis(config.sample_ms).await;
+    }
+}
\ No newline at end of file
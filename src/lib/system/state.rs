@@ -1,5 +1,165 @@
-use core::sync::atomic::{AtomicI32, AtomicU32};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::watch::Watch;
+use embassy_time::{Duration, Instant};
 
-// Atomics for sharing data between threads
-pub static TEMP_VAL: AtomicI32 = AtomicI32::new(0);
-pub static PRESSURE_VAL: AtomicU32 = AtomicU32::new(0);
+use crate::system::sensor_error::SensorUpdateError;
+
+// Latest sensor values shared between tasks
+// Sensor tasks publish() into SNAPSHOT_WATCH, consumers take a receiver to wake on new data
+// or call snapshot() for the current values
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Channel {
+    Temperature = 0,
+    Pressure = 1,
+    Humidity = 2,
+    Gas = 3,
+    Iaq = 4,
+    Lux = 5,
+    Battery = 6,
+}
+
+const CHANNELS: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct SensorSnapshot {
+    pub seq: u32,                   // Bumped on every publish
+    pub captured: Instant,          // Time of the last publish
+    valid: u8,                      // One bit per Channel, set once the channel has been sampled
+    updated: [Instant; CHANNELS],   // Time each channel was last sampled
+
+    pub temperature: i32,           // 0.01 degC
+    pub pressure: u32,              // Pa
+    pub humidity: u32,              // 0.001 %RH
+
+    pub gas: u32,                   // Ohm
+    pub iaq: u16,                   // 0 - 500
+    pub iaq_accuracy: u8,           // IaqAccuracy
+
+    pub lux: u32,                   // 0.01 lux
+
+    pub battery_mv: u16,            // mV, after smoothing
+    pub battery_level: u8,          // %
+
+    pub bme_error: Option<SensorUpdateError>,   // Latest BME680 failure, None once sampling recovers
+    pub tsl_error: Option<SensorUpdateError>,   // Same for the TSL2591
+}
+
+impl SensorSnapshot {
+    pub const EMPTY: SensorSnapshot = SensorSnapshot {
+        seq: 0,
+        captured: Instant::from_ticks(0),
+        valid: 0,
+        updated: [Instant::from_ticks(0); CHANNELS],
+        temperature: 0,
+        pressure: 0,
+        humidity: 0,
+        gas: 0,
+        iaq: 0,
+        iaq_accuracy: 0,
+        lux: 0,
+        battery_mv: 0,
+        battery_level: 0,
+        bme_error: None,
+        tsl_error: None,
+    };
+
+    pub fn is_valid(&self, channel: Channel) -> bool {
+        self.valid & (1 << channel as u8) != 0
+    }
+
+    // When the channel was last sampled, None if it never was
+    pub fn updated(&self, channel: Channel) -> Option<Instant> {
+        self.is_valid(channel).then(|| self.updated[channel as usize])
+    }
+
+    // Sampled within max_age
+    pub fn is_fresh(&self, channel: Channel, max_age: Duration) -> bool {
+        self.updated(channel).is_some_and(|at| at.elapsed() <= max_age)
+    }
+
+    // Values are None until the channel has been sampled
+    pub fn temperature(&self) -> Option<i32> {
+        self.is_valid(Channel::Temperature).then_some(self.temperature)
+    }
+
+    pub fn pressure(&self) -> Option<u32> {
+        self.is_valid(Channel::Pressure).then_some(self.pressure)
+    }
+
+    pub fn humidity(&self) -> Option<u32> {
+        self.is_valid(Channel::Humidity).then_some(self.humidity)
+    }
+
+    pub fn gas(&self) -> Option<u32> {
+        self.is_valid(Channel::Gas).then_some(self.gas)
+    }
+
+    pub fn iaq(&self) -> Option<u16> {
+        self.is_valid(Channel::Iaq).then_some(self.iaq)
+    }
+
+    pub fn lux(&self) -> Option<u32> {
+        self.is_valid(Channel::Lux).then_some(self.lux)
+    }
+
+    pub fn battery_mv(&self) -> Option<u16> {
+        self.is_valid(Channel::Battery).then_some(self.battery_mv)
+    }
+
+    pub fn battery_level(&self) -> Option<u8> {
+        self.is_valid(Channel::Battery).then_some(self.battery_level)
+    }
+
+    fn mark(&mut self, channels: &[Channel], now: Instant) {
+        for &channel in channels {
+            self.valid |= 1 << channel as u8;
+            self.updated[channel as usize] = now;
+        }
+        self.seq = self.seq.wrapping_add(1);
+        self.captured = now;
+    }
+}
+
+// Change-driven updaters each hold one receiver
+pub const SNAPSHOT_RECEIVERS: usize = 12;
+pub static SNAPSHOT_WATCH: Watch<ThreadModeRawMutex, SensorSnapshot, SNAPSHOT_RECEIVERS> = Watch::new_with(SensorSnapshot::EMPTY);
+
+pub fn snapshot() -> SensorSnapshot {
+    SNAPSHOT_WATCH.try_get().unwrap_or(SensorSnapshot::EMPTY)
+}
+
+// Write new values for the given channels and wake every receiver
+// e.g. publish(&[Channel::Lux], |s| s.lux = lux_val)
+pub fn publish(channels: &[Channel], update: impl Fn(&mut SensorSnapshot)) {
+    let now = Instant::now();
+    SNAPSHOT_WATCH.sender().send_modify(|snapshot| {
+        let snapshot = snapshot.get_or_insert(SensorSnapshot::EMPTY);
+        update(snapshot);
+        snapshot.mark(channels, now);
+    });
+}
+
+// Record the BME680 error state, receivers are only woken when it changes
+pub fn publish_error(error: Option<SensorUpdateError>) {
+    publish_status(error, |s| &mut s.bme_error);
+}
+
+// Same for the TSL2591
+pub fn publish_tsl_error(error: Option<SensorUpdateError>) {
+    publish_status(error, |s| &mut s.tsl_error);
+}
+
+fn publish_status(error: Option<SensorUpdateError>, field: impl Fn(&mut SensorSnapshot) -> &mut Option<SensorUpdateError>) {
+    SNAPSHOT_WATCH.sender().send_if_modified(|snapshot| {
+        let snapshot = snapshot.get_or_insert(SensorSnapshot::EMPTY);
+        let current = field(snapshot);
+        if *current == error {
+            return false;
+        }
+        *current = error;
+        snapshot.seq = snapshot.seq.wrapping_add(1);
+        true
+    });
+}
@@ -0,0 +1,122 @@
+// Internal flash shared by the on-chip stores
+// nrf_softdevice::Flash while the SoftDevice is enabled (it owns NVMC), NVMC directly otherwise
+use static_cell::StaticCell;
+
+use embassy_sync::mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embedded_storage::nor_flash::{NorFlash as BlockingNorFlash, ReadNorFlash as BlockingReadNorFlash};
+use embedded_storage_async::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+use embassy_hal_internal::Peri;
+use nrf_softdevice::Softdevice;
+
+use crate::embassy_hal::nvmc::Nvmc;
+use crate::embassy_hal::peripherals;
+
+pub const PAGE_SIZE: u32 = 4096;
+
+// Regions reserved at the top of the application flash
+// Keep in sync with memory/memory_ble.x and memory/memory_default.x
+pub const CONFIG_STORE_START: u32 = 0x000F_E000;
+pub const CONFIG_STORE_PAGES: u32 = 2;
+pub const HISTORY_START: u32 = 0x000E_E000;
+pub const HISTORY_PAGES: u32 = 16;
+pub const BOND_STORE_START: u32 = 0x000E_C000;
+pub const BOND_STORE_PAGES: u32 = 2;
+
+// nrf_softdevice::Flash returns Misaligned for data that isn't word aligned in RAM, and callers' stack
+// buffers usually aren't - writes through the SoftDevice are copied into an aligned buffer first
+const WRITE_CHUNK: usize = 128;
+
+#[repr(align(4))]
+struct AlignedChunk([u8; WRITE_CHUNK]);
+
+pub enum FlashDriver {
+    Softdevice(nrf_softdevice::Flash),
+    Nvmc(Nvmc<'static>),
+}
+
+pub type SharedFlash = Mutex<ThreadModeRawMutex, FlashDriver>;
+
+static FLASH: StaticCell<SharedFlash> = StaticCell::new();
+
+// Flash through the SoftDevice, call once after BLEWrapper has enabled it
+pub fn softdevice_flash() -> FlashDriver {
+    // BLEWrapper keeps the Softdevice handle to itself, it is a singleton once enabled
+    let sd = unsafe { Softdevice::steal() };
+    FlashDriver::Softdevice(nrf_softdevice::Flash::take(sd))
+}
+
+// Flash through NVMC, only for binaries that never enable the SoftDevice
+pub fn nvmc_flash(nvmc: Peri<'static, peripherals::NVMC>) -> FlashDriver {
+    FlashDriver::Nvmc(Nvmc::new(nvmc))
+}
+
+// Share one driver between every store
+pub fn init_flash(driver: FlashDriver) -> &'static SharedFlash {
+    FLASH.init(Mutex::new(driver))
+}
+
+impl ErrorType for FlashDriver {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for FlashDriver {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        match self {
+            FlashDriver::Softdevice(flash) => ReadNorFlash::read(flash, offset, bytes).await.map_err(|e| e.kind()),
+            FlashDriver::Nvmc(flash) => BlockingReadNorFlash::read(flash, offset, bytes).map_err(|e| e.kind()),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            FlashDriver::Softdevice(flash) => ReadNorFlash::capacity(flash),
+            FlashDriver::Nvmc(flash) => BlockingReadNorFlash::capacity(flash),
+        }
+    }
+}
+
+impl NorFlash for FlashDriver {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = PAGE_SIZE as usize;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        match self {
+            FlashDriver::Softdevice(flash) => NorFlash::erase(flash, from, to).await.map_err(|e| e.kind()),
+            FlashDriver::Nvmc(flash) => BlockingNorFlash::erase(flash, from, to).map_err(|e| e.kind()),
+        }
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        match self {
+            FlashDriver::Softdevice(flash) => {
+                let mut chunk = AlignedChunk([0u8; WRITE_CHUNK]);
+                let mut addr = offset;
+                for part in bytes.chunks(WRITE_CHUNK) {
+                    let buf = &mut chunk.0[..part.len()];
+                    buf.copy_from_slice(part);
+                    NorFlash::write(flash, addr, buf).await.map_err(|e| e.kind())?;
+                    addr += part.len() as u32;
+                }
+                Ok(())
+            }
+            FlashDriver::Nvmc(flash) => BlockingNorFlash::write(flash, offset, bytes).map_err(|e| e.kind()),
+        }
+    }
+}
+
+// CRC-32 (IEEE 802.3), bitwise - records are small so a table isn't worth the flash
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
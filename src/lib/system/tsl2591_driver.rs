@@ -0,0 +1,409 @@
+/// TSL2591 ambient light sensor on top of the generic Chip register helpers
+/// Lux formula and saturation limits follow the ams TSL2591 datasheet / Adafruit reference driver
+use embassy_time::Timer;
+
+use crate::embassy_hal::gpio::Input;
+use crate::d_peripherals::chip::Chip;
+use crate::d_peripherals::chip_implementations::I2CMutexWrapper;
+
+use crate::d_info;  // Logging
+
+pub const TSL2591_ADDRESS: u8 = 0x29;
+const TSL2591_ID: u8 = 0x50;
+
+// Registers are addressed directly until chip_map (d_peripherals) has TSL2591 entries - see BACKLOG.md
+// Every access needs the command bit + transaction type in the address byte, and the special function
+// commands aren't registers at all, so the Chip is created with new_generic and given the finished command byte
+const COMMAND: u8 = 0xA0;
+const ENABLE: u8 = COMMAND;             // 0x00
+const CONTROL: u8 = COMMAND | 0x01;
+const AILTL: u8 = COMMAND | 0x04;       // AILTL, AILTH, AIHTL, AIHTH
+const NPAILTL: u8 = COMMAND | 0x08;     // NPAILTL, NPAILTH, NPAIHTL, NPAIHTH
+const PERSIST: u8 = COMMAND | 0x0C;
+const ID: u8 = COMMAND | 0x12;
+const STATUS: u8 = COMMAND | 0x13;
+const C0DATAL: u8 = COMMAND | 0x14;     // C0DATAL, C0DATAH, C1DATAL, C1DATAH
+
+// Special function command - single byte write
+const CLEAR_ALL_INT: u8 = 0xE7;
+
+// ENABLE / STATUS bits
+const PON: u8 = 1 << 0;
+const AEN: u8 = 1 << 1;
+const AIEN: u8 = 1 << 4;
+const NPIEN: u8 = 1 << 7;
+const AVALID: u8 = 1 << 0;
+const AINT: u8 = 1 << 4;
+const NPINTR: u8 = 1 << 5;
+
+const LUX_DF: f32 = 408.0;              // Device factor
+const AUTO_GAIN_LOW_COUNTS: u16 = 100;  // Step up below this many full-spectrum counts
+const AUTO_GAIN_TRIES: u8 = 8;
+const AVALID_TRIES: u32 = 3;            // Integration cycles to wait for AVALID
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum TSL2591Error {
+    Bus,
+    NotFound,
+    Saturated,
+    NoInterruptPin,
+    Timeout,            // AVALID never set, the ADC isn't running (powered down / AEN off)
+}
+
+// Consecutive out-of-range cycles before the persisted ALS interrupt fires
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Persist {
+    Every = 0,      // Every cycle, regardless of thresholds
+    Any = 1,        // Any single value outside the thresholds
+    Cycles2 = 2,
+    Cycles3 = 3,
+    Cycles5 = 4,
+    Cycles10 = 5,
+    Cycles15 = 6,
+    Cycles20 = 7,
+    Cycles25 = 8,
+    Cycles30 = 9,
+    Cycles35 = 10,
+    Cycles40 = 11,
+    Cycles45 = 12,
+    Cycles50 = 13,
+    Cycles55 = 14,
+    Cycles60 = 15,
+}
+
+// What woke wait_for_threshold()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ThresholdEvent {
+    pub channels: Channels,
+    pub als: bool,          // Persisted ALS interrupt
+    pub no_persist: bool,   // No-persist interrupt
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Gain {
+    Low = 0x00,     // 1x
+    Medium = 0x10,  // 25x
+    High = 0x20,    // 428x
+    Max = 0x30,     // 9876x
+}
+
+impl Gain {
+    pub fn multiplier(self) -> f32 {
+        match self {
+            Gain::Low => 1.0,
+            Gain::Medium => 25.0,
+            Gain::High => 428.0,
+            Gain::Max => 9876.0,
+        }
+    }
+
+    fn up(self) -> Option<Self> {
+        match self {
+            Gain::Low => Some(Gain::Medium),
+            Gain::Medium => Some(Gain::High),
+            Gain::High => Some(Gain::Max),
+            Gain::Max => None,
+        }
+    }
+
+    fn down(self) -> Option<Self> {
+        match self {
+            Gain::Low => None,
+            Gain::Medium => Some(Gain::Low),
+            Gain::High => Some(Gain::Medium),
+            Gain::Max => Some(Gain::High),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum IntegrationTime {
+    Ms100 = 0,
+    Ms200 = 1,
+    Ms300 = 2,
+    Ms400 = 3,
+    Ms500 = 4,
+    Ms600 = 5,
+}
+
+impl IntegrationTime {
+    pub fn millis(self) -> u32 {
+        (self as u32 + 1) * 100
+    }
+
+    // ADC full scale - the 100 ms setting can't reach 16 bits
+    pub fn max_counts(self) -> u16 {
+        match self {
+            IntegrationTime::Ms100 => 36863,
+            _ => 65535,
+        }
+    }
+
+    fn up(self) -> Option<Self> {
+        match self {
+            IntegrationTime::Ms100 => Some(IntegrationTime::Ms200),
+            IntegrationTime::Ms200 => Some(IntegrationTime::Ms300),
+            IntegrationTime::Ms300 => Some(IntegrationTime::Ms400),
+            IntegrationTime::Ms400 => Some(IntegrationTime::Ms500),
+            IntegrationTime::Ms500 => Some(IntegrationTime::Ms600),
+            IntegrationTime::Ms600 => None,
+        }
+    }
+
+    fn down(self) -> Option<Self> {
+        match self {
+            IntegrationTime::Ms100 => None,
+            IntegrationTime::Ms200 => Some(IntegrationTime::Ms100),
+            IntegrationTime::Ms300 => Some(IntegrationTime::Ms200),
+            IntegrationTime::Ms400 => Some(IntegrationTime::Ms300),
+            IntegrationTime::Ms500 => Some(IntegrationTime::Ms400),
+            IntegrationTime::Ms600 => Some(IntegrationTime::Ms500),
+        }
+    }
+}
+
+// Full spectrum (visible + IR) and IR-only channel counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Channels {
+    pub full: u16,
+    pub ir: u16,
+}
+
+pub struct TSL2591Driver {
+    pub chip: Chip,
+    gain: Gain,
+    integration: IntegrationTime,
+    int_enable: u8,
+    int_pin: Option<Input<'static>>,
+}
+
+impl TSL2591Driver {
+
+    // Checks the chip ID and leaves the sensor powered down at medium gain / 100 ms
+    pub async fn new(i2c_bus: I2CMutexWrapper) -> Result<Self, TSL2591Error> {
+        let chip = Chip::new_generic(i2c_bus, TSL2591_ADDRESS);
+
+        let id = chip.read_reg(ID).await.map_err(|_| TSL2591Error::Bus)?;
+        if id != TSL2591_ID {
+            return Err(TSL2591Error::NotFound);
+        }
+
+        let mut tsl = TSL2591Driver {
+            chip,
+            gain: Gain::Medium,
+            integration: IntegrationTime::Ms100,
+            int_enable: 0,
+            int_pin: None,
+        };
+        tsl.power_down().await?;
+        tsl.set_timing(Gain::Medium, IntegrationTime::Ms100).await?;
+
+        Ok(tsl)
+    }
+
+    pub async fn enable(&mut self) -> Result<(), TSL2591Error> {
+        self.chip.write_reg(ENABLE, PON | AEN | self.int_enable).await.map_err(|_| TSL2591Error::Bus)
+    }
+
+    pub async fn power_down(&mut self) -> Result<(), TSL2591Error> {
+        self.chip.write_reg(ENABLE, 0).await.map_err(|_| TSL2591Error::Bus)
+    }
+
+    pub async fn set_gain(&mut self, gain: Gain) -> Result<(), TSL2591Error> {
+        self.set_timing(gain, self.integration).await
+    }
+
+    pub async fn set_integration_time(&mut self, integration: IntegrationTime) -> Result<(), TSL2591Error> {
+        self.set_timing(self.gain, integration).await
+    }
+
+    // Gain and integration time share the CONTROL register
+    pub async fn set_timing(&mut self, gain: Gain, integration: IntegrationTime) -> Result<(), TSL2591Error> {
+        self.chip.write_reg(CONTROL, gain as u8 | integration as u8).await.map_err(|_| TSL2591Error::Bus)?;
+        self.gain = gain;
+        self.integration = integration;
+
+        d_info!("TSL2591 gain: {}, integration: {} ms", gain, integration.millis());
+        Ok(())
+    }
+
+    pub fn gain(&self) -> Gain {
+        self.gain
+    }
+
+    pub fn integration_time(&self) -> IntegrationTime {
+        self.integration
+    }
+
+    // Waits one integration cycle (plus margin) until AVALID is set, then reads both channels in one transaction
+    pub async fn read_channels(&mut self) -> Result<Channels, TSL2591Error> {
+        let mut tries = 0;
+        loop {
+            Timer::after_millis(self.integration.millis() as u64 + 20).await;
+
+            let status = self.chip.read_reg(STATUS).await.map_err(|_| TSL2591Error::Bus)?;
+            if status & AVALID != 0 {
+                break;
+            }
+
+            tries += 1;
+            if tries >= AVALID_TRIES {
+                return Err(TSL2591Error::Timeout);
+            }
+        }
+
+        let mut raw = [0u8; 4];
+        self.chip.read_regs(C0DATAL, &mut raw).await.map_err(|_| TSL2591Error::Bus)?;
+
+        Ok(Channels {
+            full: u16::from_le_bytes([raw[0], raw[1]]),
+            ir: u16::from_le_bytes([raw[2], raw[3]]),
+        })
+    }
+
+    pub fn is_saturated(&self, channels: &Channels) -> bool {
+        let max_counts = self.integration.max_counts();
+        channels.full >= max_counts || channels.ir >= max_counts
+    }
+
+    // Single reading at the current gain / integration time
+    pub async fn read_lux(&mut self) -> Result<f32, TSL2591Error> {
+        let channels = self.read_channels().await?;
+        if self.is_saturated(&channels) {
+            return Err(TSL2591Error::Saturated);
+        }
+
+        Ok(lux(channels, self.gain, self.integration))
+    }
+
+    // Steps gain first, then integration time, until the reading is in range
+    // If the light keeps moving for AUTO_GAIN_TRIES steps the last unsaturated reading is returned
+    pub async fn read_lux_auto(&mut self) -> Result<f32, TSL2591Error> {
+        let mut last = None;
+        for _ in 0..AUTO_GAIN_TRIES {
+            let channels = self.read_channels().await?;
+            if !self.is_saturated(&channels) {
+                last = Some(lux(channels, self.gain, self.integration));
+            }
+
+            let (gain, integration) = if self.is_saturated(&channels) {
+                match (self.gain.down(), self.integration.down()) {
+                    (Some(gain), _) => (gain, self.integration),
+                    (None, Some(integration)) => (self.gain, integration),
+                    (None, None) => return Err(TSL2591Error::Saturated),
+                }
+            } else if channels.full < AUTO_GAIN_LOW_COUNTS {
+                match (self.gain.up(), self.integration.up()) {
+                    (Some(gain), _) => (gain, self.integration),
+                    (None, Some(integration)) => (self.gain, integration),
+                    (None, None) => return Ok(lux(channels, self.gain, self.integration)),
+                }
+            } else {
+                return Ok(lux(channels, self.gain, self.integration));
+            };
+
+            self.set_timing(gain, integration).await?;
+        }
+
+        last.ok_or(TSL2591Error::Saturated)
+    }
+
+    // INT is open drain, active low - pass the GPIOTE input with a pull-up
+    pub fn attach_interrupt(&mut self, pin: Input<'static>) {
+        self.int_pin = Some(pin);
+    }
+
+    // Hand the pin back, e.g. before the driver is dropped and created again
+    pub fn detach_interrupt(&mut self) -> Option<Input<'static>> {
+        self.int_pin.take()
+    }
+
+    // Persisted ALS interrupt on the full-spectrum channel, in raw counts
+    pub async fn set_thresholds(&mut self, low: u16, high: u16, persist: Persist) -> Result<(), TSL2591Error> {
+        self.write_thresholds(AILTL, low, high).await?;
+        self.chip.write_reg(PERSIST, persist as u8).await.map_err(|_| TSL2591Error::Bus)?;
+
+        d_info!("TSL2591 ALS thresholds: {} - {}, persist: {}", low, high, persist);
+        Ok(())
+    }
+
+    // Interrupt that fires on the first cycle outside the window
+    pub async fn set_no_persist_thresholds(&mut self, low: u16, high: u16) -> Result<(), TSL2591Error> {
+        self.write_thresholds(NPAILTL, low, high).await?;
+
+        d_info!("TSL2591 no-persist thresholds: {} - {}", low, high);
+        Ok(())
+    }
+
+    pub async fn enable_interrupts(&mut self, als: bool, no_persist: bool) -> Result<(), TSL2591Error> {
+        self.int_enable = if als { AIEN } else { 0 } | if no_persist { NPIEN } else { 0 };
+        self.clear_interrupts().await?;
+        self.enable().await
+    }
+
+    pub async fn clear_interrupts(&mut self) -> Result<(), TSL2591Error> {
+        self.special_function(CLEAR_ALL_INT).await
+    }
+
+    // Sleeps until the INT pin goes low, then reports and clears the interrupt
+    pub async fn wait_for_threshold(&mut self) -> Result<ThresholdEvent, TSL2591Error> {
+        let pin = self.int_pin.as_mut().ok_or(TSL2591Error::NoInterruptPin)?;
+        pin.wait_for_low().await;
+
+        let status = self.chip.read_reg(STATUS).await.map_err(|_| TSL2591Error::Bus)?;
+        let mut raw = [0u8; 4];
+        self.chip.read_regs(C0DATAL, &mut raw).await.map_err(|_| TSL2591Error::Bus)?;
+
+        let event = ThresholdEvent {
+            channels: Channels {
+                full: u16::from_le_bytes([raw[0], raw[1]]),
+                ir: u16::from_le_bytes([raw[2], raw[3]]),
+            },
+            als: status & AINT != 0,
+            no_persist: status & NPINTR != 0,
+        };
+
+        // Both interrupts latch until cleared, INT stays low otherwise
+        self.clear_interrupts().await?;
+
+        d_info!("TSL2591 threshold event: {}", event);
+        Ok(event)
+    }
+
+    pub fn lux(&self, channels: Channels) -> f32 {
+        lux(channels, self.gain, self.integration)
+    }
+
+    async fn write_thresholds(&mut self, reg: u8, low: u16, high: u16) -> Result<(), TSL2591Error> {
+        let [low_l, low_h] = low.to_le_bytes();
+        let [high_l, high_h] = high.to_le_bytes();
+
+        for (offset, val) in [low_l, low_h, high_l, high_h].into_iter().enumerate() {
+            self.chip.write_reg(reg + offset as u8, val).await.map_err(|_| TSL2591Error::Bus)?;
+        }
+        Ok(())
+    }
+
+    // The command byte alone triggers a special function, Chip has no bare command write so it goes
+    // out as the address byte of a register read - the byte read back is ignored
+    // A register write would follow it with a data byte and clobber the register under the address pointer
+    async fn special_function(&mut self, command: u8) -> Result<(), TSL2591Error> {
+        self.chip.read_reg(command).await.map(|_| ()).map_err(|_| TSL2591Error::Bus)
+    }
+}
+
+// Lux from raw channel counts
+pub fn lux(channels: Channels, gain: Gain, integration: IntegrationTime) -> f32 {
+    if channels.full == 0 || channels.ir >= channels.full {
+        return 0.0;
+    }
+
+    let full = channels.full as f32;
+    let ir = channels.ir as f32;
+    let cpl = (integration.millis() as f32 * gain.multiplier()) / LUX_DF;
+
+    ((full - ir) * (1.0 - ir / full)) / cpl
+}
@@ -0,0 +1,83 @@
+/// When characteristic updaters push a value
+/// Periodic - wake every notify period and always push (original behaviour)
+/// OnChange - wake when a sensor task publishes a snapshot, push only when the value moved past
+///            the deadband or a notify period passed since the last push
+/// The notify period is read from system::config on every wait, so BLE writes apply live
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::watch::Receiver;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::system::config;
+use crate::system::state::{self, SensorSnapshot, SNAPSHOT_WATCH, SNAPSHOT_RECEIVERS};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum UpdatePolicy {
+    Periodic,
+    OnChange { deadband: u32 },     // deadband is in the unit of the pushed value
+}
+
+fn notify_ms() -> u64 {
+    config::current().notify_ms as u64
+}
+
+// Waits for the next moment a value could be pushed
+pub struct UpdateTrigger {
+    receiver: Option<Receiver<'static, ThreadModeRawMutex, SensorSnapshot, SNAPSHOT_RECEIVERS>>,
+}
+
+impl UpdateTrigger {
+    pub fn new(policy: UpdatePolicy) -> Self {
+        let receiver = match policy {
+            UpdatePolicy::Periodic => None,
+            UpdatePolicy::OnChange { .. } => SNAPSHOT_WATCH.receiver(),
+        };
+        UpdateTrigger { receiver }
+    }
+
+    // Returns the latest snapshot
+    pub async fn wait(&mut self) -> SensorSnapshot {
+        let interval_ms = notify_ms();
+        match self.receiver.as_mut() {
+            Some(receiver) => match select(receiver.changed(), Timer::after_millis(interval_ms)).await {
+                Either::First(snapshot) => snapshot,
+                Either::Second(_) => state::snapshot(),
+            },
+            // Periodic, or out of receiver slots - wait out the notify period
+            None => {
+                Timer::after_millis(interval_ms).await;
+                state::snapshot()
+            }
+        }
+    }
+}
+
+// Decides whether a freshly loaded value is worth pushing
+pub struct Deadband {
+    policy: UpdatePolicy,
+    last_val: Option<i64>,
+    last_push: Instant,
+}
+
+impl Deadband {
+    pub fn new(policy: UpdatePolicy) -> Self {
+        Deadband { policy, last_val: None, last_push: Instant::now() }
+    }
+
+    pub fn check(&mut self, val: i64) -> bool {
+        let push = match (self.policy, self.last_val) {
+            (UpdatePolicy::Periodic, _) => true,
+            (UpdatePolicy::OnChange { .. }, None) => true,
+            (UpdatePolicy::OnChange { deadband }, Some(last)) => {
+                (val - last).unsigned_abs() >= deadband as u64
+                    || self.last_push.elapsed() >= Duration::from_millis(notify_ms())
+            }
+        };
+
+        if push {
+            self.last_val = Some(val);
+            self.last_push = Instant::now();
+        }
+        push
+    }
+}